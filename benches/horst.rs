@@ -0,0 +1,68 @@
+//!
+//! Benchmarks of the HORST scheme operations (key generation, signing & verification)
+//! for the parameter sets of the `debug` feature, the production and one in between.
+//!
+//! The key generation includes building the Merkle tree over all `2^TAU` leaves, which
+//! dominates its cost for the production parameters.
+//!
+//! Run with `cargo bench` (or e.g. `cargo bench -- production` for just one set).
+//!
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+// ---
+use hab::{HorstSigScheme, SignatureSchemeTrait};
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use sha3::Sha3_256;
+
+/// Size of the hashes in a Merkle tree
+const N: usize = 256 / 8;
+
+/// The parameters of the `debug` feature (K=64, TAU=4).
+type DebugScheme = HorstSigScheme<N, 64, 4, 5, 16, 20, ChaCha20Rng, Sha3_256>;
+/// A middle ground between the two (K=32, TAU=10).
+type MediumScheme = HorstSigScheme<N, 32, 10, 11, 1024, 16, ChaCha20Rng, Sha3_256>;
+/// The production parameters (K=16, TAU=16).
+type ProductionScheme = HorstSigScheme<N, 16, 16, 17, 65536, 16, ChaCha20Rng, Sha3_256>;
+
+/// A message of the size of a typical piece of the broadcasted audio.
+const MSG: &[u8] = &[0xAB; 16 * 1024];
+
+///
+/// Benchmarks the key generation, signing and verification of one parameter set
+/// (in a group called `$name`).
+///
+macro_rules! bench_scheme {
+    ($c:expr, $name:expr, $scheme:ty, $sample_size:expr) => {{
+        let mut group = $c.benchmark_group($name);
+        group.sample_size($sample_size);
+        let mut rng = ChaCha20Rng::seed_from_u64(42);
+
+        group.bench_function("gen_key_pair", |b| {
+            b.iter(|| <$scheme>::gen_key_pair(&mut rng))
+        });
+
+        let key_pair = <$scheme>::gen_key_pair(&mut rng);
+        group.bench_function("sign", |b| {
+            b.iter(|| <$scheme>::sign(black_box(MSG), &key_pair.secret, &key_pair.public))
+        });
+
+        let signature = <$scheme>::sign(MSG, &key_pair.secret, &key_pair.public);
+        assert!(<$scheme>::verify(MSG, &signature, &key_pair.public));
+        group.bench_function("verify", |b| {
+            b.iter(|| <$scheme>::verify(black_box(MSG), &signature, &key_pair.public))
+        });
+
+        group.finish();
+    }};
+}
+
+fn bench_horst(c: &mut Criterion) {
+    bench_scheme!(c, "debug", DebugScheme, 100);
+    bench_scheme!(c, "medium", MediumScheme, 50);
+    // The key generation takes a while here
+    bench_scheme!(c, "production", ProductionScheme, 10);
+}
+
+criterion_group!(benches, bench_horst);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+//!
+//! A minimal example of embedding the HAB protocol without the TUI, audio or networking.
+//!
+//! The sender and the receiver run in a single process and are connected by an in-memory
+//! channel (the `alt_output`/`alt_input` hooks) instead of UDP sockets. The sender signs
+//! a few messages and the receiver prints the verification verdict for each of them.
+//!
+//! It uses the same scheme instance as the app (`audibro::SignerInst`), so the `debug`
+//! feature selects the small (fast but insecure) parameter set and the `hash-*` features
+//! the hash function. It needs none of the audio, TUI or CLI features.
+//!
+//! Run with `cargo run --example loopback --features debug`.
+//!
+
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::Duration;
+// ---
+use audibro::{MessageAuthentication, Receiver, ReceiverParams, ReceiverTrait};
+use audibro::{Sender, SenderParams, SenderTrait, SignerInst};
+
+/// Number of messages to push through the loopback.
+const NUM_MESSAGES: usize = 5;
+
+fn main() {
+    let id_dir = std::env::temp_dir().join("audibro-loopback-example");
+    std::fs::create_dir_all(&id_dir).expect("The identity directory should be created.");
+
+    let running = Arc::new(AtomicBool::new(true));
+    let (tx, rx) = channel();
+
+    let mut sender = Sender::<SignerInst>::new(SenderParams {
+        sender_addr: "127.0.0.1:0".into(),
+        running: running.clone(),
+        seed: 42,
+        id_filename: id_dir.join("sender.bin").to_str().unwrap().to_owned(),
+        datagram_size: 1500,
+        receiver_lifetime: Duration::from_secs(10),
+        pre_cert: 1,
+        max_piece_size: 1024 * 1024,
+        key_dist: vec![vec![4, 100], vec![2, 50], vec![1, 0]],
+        key_charges: None,
+        dgram_delay: Duration::ZERO,
+        alt_output: Some(tx),
+    });
+
+    let mut receiver = Receiver::<SignerInst>::new(ReceiverParams {
+        running,
+        target_addr: "127.0.0.1:0".into(),
+        target_name: "alice".into(),
+        id_filename: id_dir.join("receiver.bin").to_str().unwrap().to_owned(),
+        distribute: None,
+        heartbeat_period: Duration::from_secs(5),
+        delivery_delay: Duration::from_millis(100),
+        frag_timeout: Duration::from_secs(10),
+        dgram_delay: Duration::ZERO,
+        receiver_lifetime: Duration::from_secs(10),
+        deliver: true,
+        alt_input: Some(rx),
+    });
+
+    for i in 0..NUM_MESSAGES {
+        let msg = format!("Hello from the loopback #{i}!");
+        sender
+            .broadcast(msg.into_bytes())
+            .expect("The message should be broadcasted.");
+
+        let block = receiver
+            .receive()
+            .expect("The message should be received.");
+        let verdict = match &block.authentication {
+            MessageAuthentication::Authenticated(id) => {
+                format!("authenticated by '{}'", id.petnames.join(","))
+            }
+            MessageAuthentication::Certified(id) => {
+                format!("certified by '{}'", id.petnames.join(","))
+            }
+            MessageAuthentication::Unverified => "unverified".to_owned(),
+        };
+        println!(
+            "[{}] {} -> {}",
+            block.seq,
+            String::from_utf8_lossy(&block.message),
+            verdict
+        );
+    }
+}
@@ -1,25 +1,47 @@
 
 use std::{
+    collections::VecDeque,
     fs::File,
-    sync::mpsc::{self, Receiver as MpscReceiver, Sender as MpscSender},
+    sync::mpsc::{Receiver as MpscReceiver, RecvTimeoutError, SendError},
     time::{Duration, Instant},
 };
 
 // ---
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Device, SupportedStreamConfig,
+    Device, Host, SupportedStreamConfig,
 };
 use minimp3::{Decoder, Frame};
-use mp3lame_encoder::{Builder, Encoder, FlushNoGap, InterleavedPcm};
-use std::io::{Cursor, Read};
+use mp3lame_encoder::{Birtate, Builder, Encoder, FlushNoGap, InterleavedPcm};
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::io::Read;
 use std::{
     fmt::Debug,
-    sync::{Arc, Mutex},
+    sync::{Arc, Condvar, Mutex},
 };
 // ---
 #[allow(unused_imports)]
 use hab::{debug, error, info, log_input, trace, warn};
+// ---
+use crate::codec::{OpusStreamEncoder, Resampler};
+use crate::config::{AudioCodec, OverflowPolicy};
+use crate::http_stream::HttpStream;
+
+/// The sample rate of the MP3 encoded audio (all the input is resampled to it).
+pub const MP3_SAMPLE_RATE: u32 = 44_100;
+/// A number of MP3 frames whose bitrate is checked to tell the bitrate of a file.
+const BITRATE_PROBE_FRAMES: usize = 100;
+/// A number of consecutive undecodable MP3 frames after which the source is abandoned.
+const MAX_DECODE_ERRORS: usize = 100;
+/// The RMS level the normalization aims at (approx. -20 dBFS).
+const NORMALIZE_TARGET_RMS: f64 = 0.1;
+/// How many buffer intervals of the microphone input are kept while the broadcast holds the capture up.
+const MAX_CAPTURED_INTERVALS: f64 = 2.0;
+/// The maximum gain the normalization applies (so that silence is not boosted into noise).
+const NORMALIZE_MAX_GAIN_DB: f64 = 20.0;
+/// How fast the normalization follows the loudness (the part of the change applied per chunk).
+const NORMALIZE_SMOOTHING: f64 = 0.3;
 
 /// Represents an MP3 file that can be broadcasted.
 #[derive(Debug)]
@@ -27,70 +49,274 @@ pub struct AudioFile {
     pub artist: String,
     pub title: String,
     pub filepath: String,
+    /// The (average) bitrate in kbps (0 if unknown).
     pub bitrate: u16,
+    /// If the bitrate varies between the frames.
+    pub vbr: bool,
+}
+
+impl AudioFile {
+    ///
+    /// Reads the bitrate (in kbps) from the headers of the first MP3 frames of the file.
+    /// Returns the average bitrate and whether it varies (i.e. the file is VBR).
+    ///
+    pub fn read_bitrate(filepath: &str) -> Option<(u16, bool)> {
+        let file = File::open(filepath).ok()?;
+        let mut decoder = Decoder::new(file);
+        let mut bitrates = vec![];
+        while bitrates.len() < BITRATE_PROBE_FRAMES {
+            match decoder.next_frame() {
+                Ok(frame) => bitrates.push(frame.bitrate as u32),
+                Err(minimp3::Error::Eof) | Err(minimp3::Error::Io(_)) => break,
+                Err(_) => continue,
+            }
+        }
+
+        let first = *bitrates.first()?;
+        let average = bitrates.iter().sum::<u32>() / bitrates.len() as u32;
+        Some((average as u16, bitrates.iter().any(|x| *x != first)))
+    }
+
+    /// A label of the file for the menus (e.g. `Artist - Title [320kbps]`).
+    pub fn label(&self) -> String {
+        match (self.bitrate, self.vbr) {
+            (0, _) => format!("{} - {}", self.artist, self.title),
+            (x, false) => format!("{} - {} [{x}kbps]", self.artist, self.title),
+            (x, true) => format!("{} - {} [VBR ~{x}kbps]", self.artist, self.title),
+        }
+    }
+}
+
+/// How the playlists are played.
+#[derive(Debug, Clone, Copy)]
+pub struct PlaylistParams {
+    /// If set, the files are played in a random order (a new one for each round).
+    pub shuffle: bool,
+    /// If set, the playlist starts over once it ends.
+    pub repeat: bool,
+    /// A seed of the shuffling (so that the order is reproducible).
+    pub seed: u64,
 }
 
 #[derive(Debug, Clone)]
 pub struct AudioSourceData {
     file: Option<String>,
+    /// Files to continue with once the current one ends (`None` if not playing a playlist).
+    queue: Option<VecDeque<String>>,
+    /// All the files of the playlist to start over with once the queue runs out (if repeated).
+    repeat: Option<Vec<String>>,
+    /// The RNG shuffling the playlist (`None` if not shuffled).
+    rng: Option<ChaCha20Rng>,
 }
 
 impl AudioSourceData {
     pub fn new_file(file: &str) -> Self {
         AudioSourceData {
             file: Some(file.to_string()),
+            queue: None,
+            repeat: None,
+            rng: None,
+        }
+    }
+
+    /// A playlist that plays the provided files one after another.
+    pub fn new_playlist(files: &[String], params: PlaylistParams) -> Option<Self> {
+        let mut rng = params
+            .shuffle
+            .then(|| ChaCha20Rng::seed_from_u64(params.seed));
+        let mut queue = files.to_vec();
+        if let Some(rng) = &mut rng {
+            shuffle(&mut queue, rng);
+        }
+
+        let mut queue: VecDeque<_> = queue.into_iter().collect();
+        let file = queue.pop_front()?;
+        Some(AudioSourceData {
+            file: Some(file),
+            queue: Some(queue),
+            repeat: params.repeat.then(|| files.to_vec()),
+            rng,
+        })
+    }
+
+    ///
+    /// Returns the source to continue with once the current one ends. A single file
+    /// is repeated and a playlist advances to the next file (or ends or starts over).
+    ///
+    fn next(&self) -> Option<Self> {
+        match &self.queue {
+            None => Some(self.clone()),
+            Some(queue) => {
+                let mut queue = queue.clone();
+                let mut rng = self.rng.clone();
+                if let (true, Some(files)) = (queue.is_empty(), &self.repeat) {
+                    let mut files = files.clone();
+                    if let Some(rng) = &mut rng {
+                        shuffle(&mut files, rng);
+                    }
+                    queue = files.into_iter().collect();
+                }
+                let file = queue.pop_front()?;
+                Some(AudioSourceData {
+                    file: Some(file),
+                    queue: Some(queue),
+                    repeat: self.repeat.clone(),
+                    rng,
+                })
+            }
+        }
+    }
+
+    fn is_playlist(&self) -> bool {
+        self.queue.is_some()
+    }
+}
+
+/// Shuffles the files in place (Fisher-Yates).
+fn shuffle(files: &mut [String], rng: &mut ChaCha20Rng) {
+    for i in (1..files.len()).rev() {
+        let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+        files.swap(i, j);
+    }
+}
+
+/// Parameters of the audio encoding.
+#[derive(Debug, Clone)]
+pub struct AudioSourceParams {
+    /// A codec used for the broadcasted audio.
+    pub codec: AudioCodec,
+    /// A target bitrate of the encoded audio (in kbps).
+    pub bitrate: u16,
+    /// A gain applied to the encoded audio (in dB).
+    pub gain_db: f64,
+    /// If set, the loudness of the encoded audio is normalized to `NORMALIZE_TARGET_RMS`.
+    pub normalize: bool,
+    /// A (part of the) name of the input device to capture; the default device if `None`.
+    pub input_device: Option<String>,
+}
+
+/// A chunk of interleaved PCM samples waiting to be encoded.
+pub struct PcmChunk {
+    pub samples: Vec<f64>,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+/// An encoder of the PCM chunks for the selected codec.
+enum AudioEncoder {
+    Mp3(Encoder, Resampler),
+    Opus(OpusStreamEncoder),
+}
+
+impl AudioEncoder {
+    fn new(params: &AudioSourceParams) -> Self {
+        match params.codec {
+            AudioCodec::Mp3 => {
+                let encoder = build_mp3_encoder(MP3_SAMPLE_RATE, params.bitrate);
+                AudioEncoder::Mp3(encoder, Resampler::new(MP3_SAMPLE_RATE))
+            }
+            AudioCodec::Opus => AudioEncoder::Opus(OpusStreamEncoder::new(params.bitrate)),
+        }
+    }
+
+    fn encode(&mut self, chunk: &PcmChunk) -> Vec<u8> {
+        match self {
+            // The encoder runs at a fixed rate whatever the source is
+            AudioEncoder::Mp3(encoder, resampler) => {
+                let stereo = to_stereo(&chunk.samples, chunk.channels);
+                let resampled = resampler.process(&stereo, chunk.sample_rate);
+                encode_waveform_f64(&resampled, 2, encoder)
+            }
+            AudioEncoder::Opus(encoder) => encoder.encode(
+                &to_stereo(&chunk.samples, chunk.channels),
+                chunk.sample_rate,
+            ),
+        }
+    }
+}
+
+///
+/// Applies the gain to the PCM chunks and encodes them. It is kept across the sources,
+/// so the encoded stream stays continuous when switching between them.
+///
+struct PcmEncoder {
+    encoder: AudioEncoder,
+    gain: Gain,
+}
+
+impl PcmEncoder {
+    fn new(params: &AudioSourceParams) -> Self {
+        PcmEncoder {
+            encoder: AudioEncoder::new(params),
+            gain: Gain::new(params.gain_db, params.normalize),
         }
     }
+
+    fn encode(&mut self, mut chunk: PcmChunk) -> Vec<u8> {
+        self.gain.apply(&mut chunk.samples, chunk.channels);
+        self.encoder.encode(&chunk)
+    }
 }
 
-pub struct AudioSource {}
+pub struct AudioSource {
+    /// A name of the input device used for the microphone input.
+    device_name: String,
+}
 
 impl AudioSource {
-    pub fn new(rx: MpscReceiver<AudioSourceData>, data_tx: MpscSender<Vec<u8>>) -> Self {
+    pub fn new(
+        rx: MpscReceiver<AudioSourceData>,
+        data_tx: AudioQueueSender,
+        params: AudioSourceParams,
+    ) -> Self {
         let buffer_interval = 2.0;
+        // The MP3 files can be streamed as they are unless the audio needs to be changed
+        let passthrough =
+            params.codec == AudioCodec::Mp3 && params.gain_db == 0.0 && !params.normalize;
 
-        // Get the input device
+        // Get the input device (a headless machine may have none, only files can be played then)
         let host = cpal::default_host();
-        let device = host
-            .default_input_device()
-            .expect("Failed to get default input device");
-        let config = device
-            .default_input_config()
-            .expect("Failed to get default input config");
-        let config_clone = config.clone();
-        info!("Default input device: {:?}", device.name());
-        info!("Default input format: {:?}", config);
-        let data_tx_clone = data_tx.clone();
-
-        let (txx, rxx) = mpsc::channel::<Vec<f64>>();
-        // Spawn a new thread
-        std::thread::spawn(move || {
-            let num_channels = config_clone.channels();
-            let sample_rate = config_clone.sample_rate().0;
-            let mut mp3_encoder = build_mp3_encoder(sample_rate);
-            loop {
-                let received = rxx.recv().unwrap();
-                let mp3_buffer = encode_waveform_f64(&received, num_channels, &mut mp3_encoder);
-                data_tx_clone.send(mp3_buffer).expect("!");
+        let input = select_input_device(&host, params.input_device.as_deref()).and_then(|device| {
+            match device.default_input_config() {
+                Ok(config) => Some((device, config)),
+                Err(e) => {
+                    warn!("Failed to get the input config! ERROR: {e}");
+                    None
+                }
             }
         });
+        let device_name = match &input {
+            Some((device, config)) => {
+                info!("Input format: {:?}", config);
+                device.name().unwrap_or_default()
+            }
+            None => "none".to_owned(),
+        };
+        info!("Input device: {:?}", device_name);
 
-        // Spawn audio processing
+        // Spawn audio processing (the audio is encoded right away, so the only queue
+        // in front of the broadcast is the bounded `data_tx`)
         std::thread::spawn(move || {
             let mut currently_playing: Option<AudioSourceData> = None;
+            let mut encoder = PcmEncoder::new(&params);
 
             loop {
                 if let Some(curr_play) = currently_playing.clone() {
                     // Microphone input
                     if curr_play.file.as_ref().unwrap() == "MICROPHONE" {
-                        stream_mic(
-                            &device,
-                            config.clone(),
-                            &rx,
-                            &mut currently_playing,
-                            buffer_interval,
-                            txx.clone(),
-                        );
+                        if let Some((device, config)) = &input {
+                            stream_mic(
+                                device,
+                                config.clone(),
+                                &rx,
+                                &mut currently_playing,
+                                buffer_interval,
+                                &mut |chunk| data_tx.send(encoder.encode(chunk)).expect("!"),
+                            );
+                        } else {
+                            error!("There is no input device to capture the microphone from!");
+                            currently_playing = None;
+                        }
                     }
                     // MP3 file input
                     else {
@@ -100,7 +326,13 @@ impl AudioSource {
                             &mut currently_playing,
                             buffer_interval,
                             &data_tx,
+                            passthrough,
+                            &mut encoder,
                         );
+                        // The next track of a playlist must follow without a gap
+                        if currently_playing.as_ref().is_some_and(|x| x.is_playlist()) {
+                            continue;
+                        }
                     }
                 } else if let Ok(audio_data) = rx.recv() {
                     currently_playing = Some(audio_data);
@@ -110,8 +342,169 @@ impl AudioSource {
             }
         });
 
-        Self {}
+        Self { device_name }
+    }
+
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+}
+
+///
+/// Creates a queue of the encoded audio waiting for the broadcast that holds at most
+/// `bound` pieces. Once it is full, the `policy` decides what happens to the new audio.
+///
+pub fn audio_queue(bound: usize, policy: OverflowPolicy) -> (AudioQueueSender, AudioQueueReceiver) {
+    let queue = Arc::new(AudioQueue {
+        state: Mutex::new(AudioQueueState {
+            pieces: VecDeque::new(),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        changed: Condvar::new(),
+        bound: bound.max(1),
+        policy,
+    });
+    (
+        AudioQueueSender {
+            queue: queue.clone(),
+        },
+        AudioQueueReceiver { queue },
+    )
+}
+
+struct AudioQueue {
+    state: Mutex<AudioQueueState>,
+    /// Notified whenever a piece is enqueued or dequeued (or one of the sides is dropped).
+    changed: Condvar,
+    bound: usize,
+    policy: OverflowPolicy,
+}
+
+struct AudioQueueState {
+    pieces: VecDeque<Vec<u8>>,
+    /// Number of the living senders, the receiver is disconnected once there are none.
+    senders: usize,
+    receiver_alive: bool,
+}
+
+pub struct AudioQueueSender {
+    queue: Arc<AudioQueue>,
+}
+
+impl AudioQueueSender {
+    ///
+    /// Enqueues the piece; if the queue is full, it waits or drops the oldest piece
+    /// (depending on the policy).
+    ///
+    pub fn send(&self, data: Vec<u8>) -> Result<(), SendError<Vec<u8>>> {
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if !state.receiver_alive {
+                return Err(SendError(data));
+            }
+            if state.pieces.len() < self.queue.bound {
+                state.pieces.push_back(data);
+                self.queue.changed.notify_all();
+                return Ok(());
+            }
+
+            match self.queue.policy {
+                OverflowPolicy::Block => state = self.queue.changed.wait(state).unwrap(),
+                OverflowPolicy::DropOldest => {
+                    state.pieces.pop_front();
+                    warn!("The broadcast can't keep up, dropping the oldest audio!");
+                }
+            }
+        }
+    }
+}
+
+impl Clone for AudioQueueSender {
+    fn clone(&self) -> Self {
+        self.queue.state.lock().unwrap().senders += 1;
+        AudioQueueSender {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl Drop for AudioQueueSender {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().senders -= 1;
+        self.queue.changed.notify_all();
+    }
+}
+
+pub struct AudioQueueReceiver {
+    queue: Arc<AudioQueue>,
+}
+
+impl AudioQueueReceiver {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some(x) = state.pieces.pop_front() {
+                self.queue.changed.notify_all();
+                return Ok(x);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = self
+                .queue
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Drop for AudioQueueReceiver {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().receiver_alive = false;
+        self.queue.changed.notify_all();
+    }
+}
+
+///
+/// Selects the first input device whose name contains `name`. If no name is provided
+/// or no such device exists, the default input device is used (if any).
+///
+fn select_input_device(host: &Host, name: Option<&str>) -> Option<Device> {
+    if let Some(name) = name {
+        let mut devices: Vec<_> = host
+            .input_devices()
+            .map(|x| x.collect())
+            .unwrap_or_default();
+        let names: Vec<_> = devices
+            .iter()
+            .map(|x| x.name().unwrap_or_default())
+            .collect();
+        match find_device(&names, name) {
+            Some(i) => return Some(devices.swap_remove(i)),
+            None => warn!(
+                "No input device matches '{name}', using the default one! Available: {names:?}"
+            ),
+        }
     }
+
+    host.default_input_device()
+}
+
+///
+/// Returns the index of the first of the device `names` that contains `name`. If there is
+/// no such device, `None` is returned and the default device should be used.
+///
+pub fn find_device(names: &[String], name: &str) -> Option<usize> {
+    names.iter().position(|x| x.contains(name))
 }
 
 fn stream_mic(
@@ -120,7 +513,7 @@ fn stream_mic(
     rx: &MpscReceiver<AudioSourceData>,
     currently_playing: &mut Option<AudioSourceData>,
     buffer_interval: f64,
-    txx: MpscSender<Vec<f64>>,
+    send_pcm: &mut dyn FnMut(PcmChunk),
 ) {
     match config.sample_format() {
         cpal::SampleFormat::F64 => run::<f64>(
@@ -129,7 +522,7 @@ fn stream_mic(
             rx,
             currently_playing,
             buffer_interval,
-            txx,
+            send_pcm,
             |x| x,
         ),
         cpal::SampleFormat::F32 => run::<f32>(
@@ -138,7 +531,7 @@ fn stream_mic(
             rx,
             currently_playing,
             buffer_interval,
-            txx,
+            send_pcm,
             f32_to_f64,
         ),
         cpal::SampleFormat::I16 => run::<i16>(
@@ -147,7 +540,7 @@ fn stream_mic(
             rx,
             currently_playing,
             buffer_interval,
-            txx,
+            send_pcm,
             i16_to_f64,
         ),
         cpal::SampleFormat::U16 => run::<u16>(
@@ -156,34 +549,129 @@ fn stream_mic(
             rx,
             currently_playing,
             buffer_interval,
-            txx,
+            send_pcm,
             u16_to_f64,
         ),
         _ => panic!("Unsupported sample format"),
     };
 }
 
+/// A reader that keeps a copy of the bytes read through it (so the raw MP3 data can be passed on).
+struct RecordingReader<R: Read> {
+    inner: R,
+    recorded: Vec<u8>,
+}
+
+impl<R: Read> RecordingReader<R> {
+    fn new(inner: R) -> Self {
+        RecordingReader {
+            inner,
+            recorded: vec![],
+        }
+    }
+
+    /// Returns the bytes read since the last call.
+    fn take_recorded(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.recorded)
+    }
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+///
+/// Paces the audio to the real time. The deadlines are relative to the start, so the time
+/// spent decoding & sending does not add up over a long track.
+///
+struct Pacer {
+    start: Instant,
+}
+
+impl Pacer {
+    fn new() -> Self {
+        Pacer {
+            start: Instant::now(),
+        }
+    }
+
+    /// Waits until the audio of `played` seconds (since the start) is due.
+    fn wait(&self, played: f64) {
+        let deadline = self.start + Duration::from_secs_f64(played);
+        std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Counts the MP3 frames that failed to decode in a row.
+#[derive(Debug, Default)]
+struct DecodeErrors(usize);
+
+impl DecodeErrors {
+    fn on_frame(&mut self) {
+        self.0 = 0;
+    }
+
+    /// Records an undecodable frame and tells if the source should be abandoned.
+    fn on_error(&mut self) -> bool {
+        self.0 += 1;
+        self.0 >= MAX_DECODE_ERRORS
+    }
+}
+
 fn stream_mp3(
     curr_play: &AudioSourceData,
     rx: &MpscReceiver<AudioSourceData>,
     currently_playing: &mut Option<AudioSourceData>,
     buffer_interval: f64,
-    data_tx: &MpscSender<Vec<u8>>,
+    data_tx: &AudioQueueSender,
+    passthrough: bool,
+    encoder: &mut PcmEncoder,
 ) {
-    // Open the MP3 file.
-    let mut file_data = Vec::new();
-    let mut file =
-        File::open(curr_play.file.as_ref().unwrap()).expect("Failed to open the MP3 file");
-    file.read_to_end(&mut file_data)
-        .expect("Failed to read MP3 file data");
-    let file_data_clone = file_data.clone();
-
-    let mut decoder = Decoder::new(Cursor::new(file_data));
+    // Open the MP3 source (a local file or a remote stream).
+    let location = curr_play.file.as_ref().unwrap();
+    let source: Box<dyn Read> = if HttpStream::is_url(location) {
+        match HttpStream::open(location) {
+            Ok(x) => Box::new(x),
+            Err(e) => {
+                error!("Failed to open the stream '{location}'! ERROR: {e}");
+                *currently_playing = None;
+                return;
+            }
+        }
+    } else {
+        Box::new(File::open(location).expect("Failed to open the MP3 file"))
+    };
+
+    let mut decoder = Decoder::new(RecordingReader::new(source));
     let mut current_duration = 0.0;
     let mut prev_duration = 0.0;
+    // The decoded samples for re-encoding (if not passing the MP3 frames through as they are)
+    let mut pcm = vec![];
+    // The channel count and sample rate of the last decoded frame
+    let mut format = (2, 44100);
+    let mut decode_errors = DecodeErrors::default();
+
+    let pacer = Pacer::new();
+
+    // Waits until the audio decoded so far (`played` seconds) is due and passes the data on
+    let mut send = |raw_data: Vec<u8>, pcm: Vec<f64>, played: f64, (channels, sample_rate)| {
+        pacer.wait(played);
+        let data = if passthrough {
+            raw_data
+        } else {
+            encoder.encode(PcmChunk {
+                samples: pcm,
+                channels,
+                sample_rate,
+            })
+        };
+        data_tx.send(data).expect("!");
+    };
 
-    // Save the current position in the input data.
-    let mut frame_start = decoder.reader().position() as usize;
     loop {
         if let Ok(audio_data) = rx.try_recv() {
             *currently_playing = Some(audio_data);
@@ -191,7 +679,6 @@ fn stream_mp3(
             return;
         }
 
-        let prepos = decoder.reader().position() as usize;
         // Decode the next frame.
         match decoder.next_frame() {
             Ok(Frame {
@@ -200,101 +687,149 @@ fn stream_mp3(
                 channels,
                 ..
             }) => {
-                // Update the current position in the input data.
-                let current_position = decoder.reader().position() as usize;
-
+                decode_errors.on_frame();
                 // Calculate frame duration based on frame samples
                 let frame_duration = data.len() as f64 / (sample_rate * channels as i32) as f64;
 
                 current_duration += frame_duration;
-                if current_position > prepos && current_duration >= prev_duration + buffer_interval
-                {
-                    // Calculate the raw frame data.
-                    let raw_frame_data = &file_data_clone[frame_start..current_position];
-                    // 	warn!(
-                    // 	"Frame [{frame_start}, {current_position}) with size {} to duration {}.",
-                    // 	raw_frame_data.len(),
-                    // 	current_duration
-                    // );
-
-                    frame_start = current_position;
-                    let interval_played = current_duration - prev_duration;
+                format = (channels as u16, sample_rate as u32);
+                if !passthrough {
+                    pcm.extend(data.iter().map(|x| *x as f64 / i16::MAX as f64));
+                }
+                if current_duration >= prev_duration + buffer_interval {
+                    // The raw MP3 data read since the last send
+                    let raw_frame_data = decoder.reader_mut().take_recorded();
+
                     prev_duration = current_duration;
 
-                    // Sleep for the interval duration to simulate processing.
-                    std::thread::sleep(Duration::from_secs_f64(interval_played));
-                    data_tx.send(raw_frame_data.to_vec()).expect("!");
+                    send(raw_frame_data, std::mem::take(&mut pcm), current_duration, format);
                 }
             }
             Err(minimp3::Error::Eof) => {
-                // The end of the file has been reached.
+                // The end of the file has been reached, pass on the rest of the data
+                if current_duration > prev_duration {
+                    let raw_frame_data = decoder.reader_mut().take_recorded();
+                    send(raw_frame_data, pcm, current_duration, format);
+                }
+
+                *currently_playing = curr_play.next();
+                if let Some(next) = currently_playing.as_ref().filter(|x| x.is_playlist()) {
+                    warn!("Continuing with '{:?}'...", next.file);
+                }
+                return;
+            }
+            Err(minimp3::Error::Io(e)) => {
+                error!("Failed to read from '{location}'! ERROR: {e}");
+                *currently_playing = None;
                 return;
             }
             Err(e) => {
-                eprintln!("Error decoding MP3 frame: {:?}", e);
+                warn!("Error decoding MP3 frame from '{location}': {e:?}");
+                if decode_errors.on_error() {
+                    error!("Too many errors decoding '{location}', stopping it!");
+                    *currently_playing = None;
+                    return;
+                }
             }
         }
     }
 }
 
+///
+/// Maps the interleaved samples with `num_channels` channels to interleaved stereo
+/// (i.e. `[L0, R0, L1, R1, ...]`) with the same number of frames.
+///
+/// Mono is duplicated to both channels, stereo is kept as it is and more channels
+/// are downmixed by averaging all the channels of a frame into both output channels.
+/// An incomplete trailing frame is dropped.
+///
+fn to_stereo(wave_buffer: &[f64], num_channels: u16) -> Vec<f64> {
+    let num_channels = num_channels as usize;
+    let mut stereo = Vec::with_capacity(wave_buffer.len() / num_channels.max(1) * 2);
+
+    match num_channels {
+        0 => {}
+        1 => {
+            for w in wave_buffer {
+                stereo.push(*w);
+                stereo.push(*w);
+            }
+        }
+        2 => stereo.extend_from_slice(&wave_buffer[..wave_buffer.len() - wave_buffer.len() % 2]),
+        _ => {
+            for frame in wave_buffer.chunks_exact(num_channels) {
+                let mixed = frame.iter().sum::<f64>() / num_channels as f64;
+                stereo.push(mixed);
+                stereo.push(mixed);
+            }
+        }
+    }
+
+    stereo
+}
+
 fn encode_waveform_f64(
     wave_buffer: &[f64],
     num_channels: u16,
     mp3_encoder: &mut mp3lame_encoder::Encoder,
 ) -> Vec<u8> {
-	
-	let mut new_wave_buffer = vec![];
-
-    if num_channels == 1 {
-		
-        for w in wave_buffer {
-			new_wave_buffer.push(*w);
-            new_wave_buffer.push(*w);
-        }
-    } else if num_channels == 2 {
-		new_wave_buffer.extend_from_slice(wave_buffer);
-	} else {
-		for (i, w) in wave_buffer.iter().enumerate() {
-			if i % num_channels as usize == 0 {
-				new_wave_buffer.push(*w);
-			}
-			if i % num_channels as usize == 1 {
-				new_wave_buffer.push(*w);
-			}
-        }
-	}
-	let wave_buffer = &new_wave_buffer;
-	let num_channels = 2;
-
-	let input = InterleavedPcm(&wave_buffer);
-	let mut mp3_out_buffer = Vec::new();
-	mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(
-		input.0.len() / num_channels as usize
-	));
-
-	let encoded_size = mp3_encoder
-		.encode(input, mp3_out_buffer.spare_capacity_mut())
-		.expect("To encode");
-	unsafe {
-		mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
-	}
-	let encoded_size = mp3_encoder
-		.flush::<FlushNoGap>(mp3_out_buffer.spare_capacity_mut())
-		.expect("to flush");
-	unsafe {
-		mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
-	}
-	return mp3_out_buffer;
-}
-
-fn build_mp3_encoder(sample_rate: u32) -> Encoder {
+    let wave_buffer = &to_stereo(wave_buffer, num_channels);
+    let num_channels = 2;
+
+    let input = InterleavedPcm(wave_buffer);
+    let mut mp3_out_buffer = Vec::new();
+    mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(
+        input.0.len() / num_channels as usize,
+    ));
+
+    let encoded_size = mp3_encoder
+        .encode(input, mp3_out_buffer.spare_capacity_mut())
+        .expect("To encode");
+    unsafe {
+        mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+    }
+    let encoded_size = mp3_encoder
+        .flush::<FlushNoGap>(mp3_out_buffer.spare_capacity_mut())
+        .expect("to flush");
+    unsafe {
+        mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+    }
+    mp3_out_buffer
+}
+
+///
+/// Maps the bitrate in kbps to the LAME bitrate (it must be one of `config::MP3_BITRATES`).
+///
+fn mp3_bitrate(kbps: u16) -> Birtate {
+    match kbps {
+        8 => Birtate::Kbps8,
+        16 => Birtate::Kbps16,
+        24 => Birtate::Kbps24,
+        32 => Birtate::Kbps32,
+        40 => Birtate::Kbps40,
+        48 => Birtate::Kbps48,
+        64 => Birtate::Kbps64,
+        80 => Birtate::Kbps80,
+        96 => Birtate::Kbps96,
+        112 => Birtate::Kbps112,
+        128 => Birtate::Kbps128,
+        160 => Birtate::Kbps160,
+        192 => Birtate::Kbps192,
+        224 => Birtate::Kbps224,
+        256 => Birtate::Kbps256,
+        320 => Birtate::Kbps320,
+        x => panic!("Unsupported MP3 bitrate {x}kbps!"),
+    }
+}
+
+fn build_mp3_encoder(sample_rate: u32, bitrate: u16) -> Encoder {
     let mut mp3_encoder = Builder::new().expect("Create LAME builder");
     mp3_encoder.set_num_channels(2).expect("set channels");
     mp3_encoder
         .set_sample_rate(sample_rate)
         .expect("set sample rate");
     mp3_encoder
-        .set_brate(mp3lame_encoder::Birtate::Kbps320)
+        .set_brate(mp3_bitrate(bitrate))
         .expect("set brate");
     mp3_encoder
         .set_quality(mp3lame_encoder::Quality::Best)
@@ -308,21 +843,29 @@ fn run<T>(
     rx: &MpscReceiver<AudioSourceData>,
     currently_playing: &mut Option<AudioSourceData>,
     buffer_interval: f64,
-    txx: MpscSender<Vec<f64>>,
+    send_pcm: &mut dyn FnMut(PcmChunk),
     f: impl Fn(Vec<T>) -> Vec<f64>,
 ) where
     T: cpal::Sample + cpal::SizedSample + Debug + Sync + Send + 'static,
 {
+    let channels = config.channels();
+    let sample_rate = config.sample_rate().0;
     let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
 
     let buffer = Arc::new(Mutex::new(Vec::<T>::new()));
     let buffer2 = buffer.clone();
+    let max_buffered = (MAX_CAPTURED_INTERVALS * buffer_interval * sample_rate as f64) as usize
+        * channels as usize;
 
     let stream = device
         .build_input_stream(
             &config.into(),
             move |data: &[T], _: &cpal::InputCallbackInfo| {
-                buffer2.lock().unwrap().extend_from_slice(data);
+                let mut buffer = buffer2.lock().unwrap();
+                buffer.extend_from_slice(data);
+                // The microphone can't wait, so the oldest audio goes if the broadcast holds it up
+                let excess = buffer.len().saturating_sub(max_buffered);
+                buffer.drain(..excess);
             },
             err_fn,
             None,
@@ -344,11 +887,87 @@ fn run<T>(
         until += Duration::from_secs_f64(buffer_interval);
         let mut data = buffer.lock().unwrap();
         let data_cpy = std::mem::take(&mut *data);
+        // The callback must not wait for the broadcast
+        drop(data);
         let wave_buffer = f(data_cpy);
-        txx.send(wave_buffer).unwrap();
+        send_pcm(PcmChunk {
+            samples: wave_buffer,
+            channels,
+            sample_rate,
+        });
+    }
+}
+
+///
+/// Scales the consecutive chunks of samples by the gain in dB. If `normalize` is set, the samples
+/// are also scaled towards `NORMALIZE_TARGET_RMS`; that gain follows the loudness gradually
+/// and is ramped within a chunk so that the level doesn't jump between the chunks.
+/// The result is clamped to [-1, 1].
+///
+struct Gain {
+    /// The fixed gain (as a factor).
+    fixed: f64,
+    normalize: bool,
+    /// The normalization gain at the end of the last chunk (`None` before the first one).
+    level: Option<f64>,
+}
+
+impl Gain {
+    fn new(gain_db: f64, normalize: bool) -> Self {
+        Gain {
+            fixed: 10_f64.powf(gain_db / 20.0),
+            normalize,
+            level: None,
+        }
+    }
+
+    fn apply(&mut self, samples: &mut [f64], channels: u16) {
+        if self.fixed == 1.0 && !self.normalize {
+            return;
+        }
+
+        let (from, to) = match self.normalize.then(|| normalize_gain(samples)).flatten() {
+            Some(target) => {
+                let from = self.level.unwrap_or(target);
+                let to = from + (target - from) * NORMALIZE_SMOOTHING;
+                self.level = Some(to);
+                (from, to)
+            }
+            // Silence keeps the current level
+            None => {
+                let level = self.level.unwrap_or(1.0);
+                (level, level)
+            }
+        };
+
+        let channels = (channels as usize).max(1);
+        let num_frames = (samples.len() / channels).max(1) as f64;
+        for (i, frame) in samples.chunks_mut(channels).enumerate() {
+            let gain = self.fixed * (from + (to - from) * i as f64 / num_frames);
+            for x in frame {
+                *x = (*x * gain).clamp(-1.0, 1.0);
+            }
+        }
     }
 }
 
+///
+/// The gain that brings the samples to `NORMALIZE_TARGET_RMS`, limited so that they do not clip
+/// and silence is not boosted into noise (`None` for digital silence).
+///
+fn normalize_gain(samples: &[f64]) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+    let rms = (samples.iter().map(|x| x * x).sum::<f64>() / samples.len() as f64).sqrt();
+    let peak = samples.iter().fold(0.0_f64, |acc, x| acc.max(x.abs()));
+    if rms == 0.0 {
+        return None;
+    }
+    let max_gain = 10_f64.powf(NORMALIZE_MAX_GAIN_DB / 20.0).min(1.0 / peak);
+    Some((NORMALIZE_TARGET_RMS / rms).min(max_gain))
+}
+
 fn u16_to_f64(data: Vec<u16>) -> Vec<f64> {
     data.into_iter()
         .map(|x| (x as f64 - u16::MAX as f64 / 2.0) / (u16::MAX as f64 / 2.0))
@@ -364,3 +983,360 @@ fn i16_to_f64(data: Vec<i16>) -> Vec<f64> {
 fn f32_to_f64(data: Vec<f32>) -> Vec<f64> {
     data.into_iter().map(|x| x as f64).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// A path in the temporary directory unique to this test run.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("audibro-{}-{name}", std::process::id()))
+    }
+
+    /// Writes an MP3 file of the stereo sine encoded by the segments of `(seconds, kbps)`.
+    fn write_mp3(path: &Path, segments: &[(f64, u16)]) {
+        let mut file = File::create(path).unwrap();
+        for (secs, bitrate) in segments {
+            let mut encoder = build_mp3_encoder(MP3_SAMPLE_RATE, *bitrate);
+            let samples: Vec<_> = (0..(secs * MP3_SAMPLE_RATE as f64) as usize)
+                .map(|i| 0.5 * (i as f64 * 0.05).sin())
+                .flat_map(|x| [x, x])
+                .collect();
+            file.write_all(&encode_waveform_f64(&samples, 2, &mut encoder))
+                .unwrap();
+        }
+    }
+
+    /// Re-encodes the MP3 file into a queue of `bound` pieces on a new thread.
+    fn stream_file(
+        path: &Path,
+        bound: usize,
+        policy: OverflowPolicy,
+    ) -> (std::thread::JoinHandle<()>, AudioQueueReceiver) {
+        let (data_tx, data_rx) = audio_queue(bound, policy);
+        let source = AudioSourceData::new_file(path.to_str().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (_tx, rx) = mpsc::channel();
+            let mut encoder = PcmEncoder::new(&AudioSourceParams {
+                codec: AudioCodec::Mp3,
+                bitrate: 128,
+                gain_db: 0.0,
+                normalize: false,
+                input_device: None,
+            });
+            stream_mp3(&source, &rx, &mut None, 0.05, &data_tx, false, &mut encoder);
+        });
+        (handle, data_rx)
+    }
+
+    /// Receives the pieces until the queue disconnects and counts them.
+    fn drain(rx: &AudioQueueReceiver) -> usize {
+        std::iter::from_fn(|| rx.recv_timeout(Duration::from_secs(1)).ok()).count()
+    }
+
+    fn rms(samples: &[f64]) -> f64 {
+        (samples.iter().map(|x| x * x).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    /// A stereo sine of 100 frames with the amplitude `amp`.
+    fn sine(amp: f64) -> Vec<f64> {
+        (0..100)
+            .map(|i| amp * (i as f64 * 0.3).sin())
+            .flat_map(|x| [x, x])
+            .collect()
+    }
+
+    /// The names of `n` files.
+    fn files(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{i}.mp3")).collect()
+    }
+
+    /// The first `n` files played (fewer if it ends).
+    fn play_order(source: Option<AudioSourceData>, n: usize) -> Vec<String> {
+        std::iter::successors(source, |x| x.next())
+            .take(n)
+            .map(|x| x.file.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_read_bitrate() {
+        let bitrate = |path: &PathBuf| AudioFile::read_bitrate(path.to_str().unwrap());
+        assert_eq!(
+            bitrate(&PathBuf::from("data/fun-punk-opener.mp3")),
+            Some((256, false))
+        );
+
+        let path = temp_path("bitrate.mp3");
+        write_mp3(&path, &[(1.0, 192)]);
+        assert_eq!(bitrate(&path), Some((192, false)));
+
+        // Two halves of a different bitrate average in between
+        write_mp3(&path, &[(1.0, 128), (1.0, 320)]);
+        let (average, vbr) = bitrate(&path).unwrap();
+        assert!(vbr);
+        assert!(128 < average && average < 320, "{average}");
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bitrate(&path), None);
+    }
+
+    #[test]
+    fn test_playlist_order() {
+        let params = PlaylistParams {
+            shuffle: false,
+            repeat: false,
+            seed: 42,
+        };
+
+        // The files of the ALL playlist follow one another and then it ends
+        let playlist = AudioSourceData::new_playlist(&files(3), params);
+        assert_eq!(play_order(playlist, 10), files(3));
+        assert!(AudioSourceData::new_playlist(&[], params).is_none());
+
+        // A single file is repeated
+        let single = AudioSourceData::new_file("0.mp3");
+        assert_eq!(play_order(Some(single), 3), ["0.mp3"; 3]);
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let shuffled = |seed| {
+            let mut files = files(10);
+            shuffle(&mut files, &mut ChaCha20Rng::seed_from_u64(seed));
+            files
+        };
+
+        // The same seed gives the same order, which is a permutation of the files
+        assert_eq!(shuffled(42), shuffled(42));
+        assert_ne!(shuffled(42), shuffled(43));
+        let mut sorted = shuffled(42);
+        sorted.sort();
+        assert_eq!(sorted, files(10));
+    }
+
+    #[test]
+    fn test_playlist_repeat() {
+        let params = PlaylistParams {
+            shuffle: false,
+            repeat: true,
+            seed: 42,
+        };
+        let playlist = AudioSourceData::new_playlist(&files(3), params);
+        let expected = [
+            "0.mp3", "1.mp3", "2.mp3", "0.mp3", "1.mp3", "2.mp3", "0.mp3",
+        ];
+        assert_eq!(play_order(playlist, 7), expected);
+
+        // Each round is shuffled anew, but reproducibly
+        let params = PlaylistParams {
+            shuffle: true,
+            ..params
+        };
+        let order = play_order(AudioSourceData::new_playlist(&files(5), params), 15);
+        assert_eq!(
+            order,
+            play_order(AudioSourceData::new_playlist(&files(5), params), 15)
+        );
+        for round in order.chunks(5) {
+            let mut round = round.to_vec();
+            round.sort();
+            assert_eq!(round, files(5));
+        }
+    }
+
+    #[test]
+    fn test_to_stereo() {
+        // Mono is duplicated to both channels
+        assert_eq!(
+            to_stereo(&[0.1, 0.2, 0.3], 1),
+            [0.1, 0.1, 0.2, 0.2, 0.3, 0.3]
+        );
+
+        // Stereo is kept (without the incomplete frame)
+        assert_eq!(
+            to_stereo(&[0.1, 0.2, 0.3, 0.4, 0.5], 2),
+            [0.1, 0.2, 0.3, 0.4]
+        );
+
+        // 5.1 is averaged into both channels
+        let surround = [
+            0.6, 0.0, 0.3, 0.3, 0.0, 0.0, -0.6, -0.6, 0.0, 0.0, 0.0, 0.0, 0.9,
+        ];
+        let stereo = to_stereo(&surround, 6);
+        assert_eq!(stereo.len(), 4);
+        assert!((stereo[0] - 0.2).abs() < 1e-9 && (stereo[1] - 0.2).abs() < 1e-9);
+        assert!((stereo[2] + 0.2).abs() < 1e-9 && (stereo[3] + 0.2).abs() < 1e-9);
+
+        assert!(to_stereo(&[0.1, 0.2], 0).is_empty());
+    }
+
+    #[test]
+    fn test_find_device() {
+        let names: Vec<String> = ["default", "USB Audio Device", "sysdefault:CARD=USB"]
+            .into_iter()
+            .map(|x| x.into())
+            .collect();
+
+        // The first one containing the name
+        assert_eq!(find_device(&names, "USB"), Some(1));
+        assert_eq!(find_device(&names, "CARD=USB"), Some(2));
+        assert_eq!(find_device(&names, "default"), Some(0));
+
+        // Else the default one is used
+        assert_eq!(find_device(&names, "usb audio"), None);
+        assert_eq!(find_device(&[], "USB"), None);
+    }
+
+    #[test]
+    fn test_pacer_no_drift() {
+        let pacer = Pacer::new();
+        for i in 1..=10 {
+            // The decoding & sending takes a while
+            std::thread::sleep(Duration::from_millis(40));
+            pacer.wait(i as f64 * 0.05);
+        }
+
+        // 0.5s of audio takes 0.5s (plus the work of the last round), not 0.9s with
+        // the processing time added up; the slack is for a busy machine oversleeping
+        let elapsed = pacer.start.elapsed().as_secs_f64();
+        assert!(elapsed >= 0.5, "It took {elapsed}s!");
+        assert!(elapsed < 0.5 + 0.04 + 0.2, "It took {elapsed}s!");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut errors = DecodeErrors::default();
+        for _ in 1..MAX_DECODE_ERRORS {
+            assert!(!errors.on_error());
+        }
+        // A decoded frame starts the count over
+        errors.on_frame();
+        for _ in 1..MAX_DECODE_ERRORS {
+            assert!(!errors.on_error());
+        }
+        assert!(errors.on_error());
+    }
+
+    #[test]
+    fn test_gain_fixed() {
+        let mut samples = vec![0.25, -0.25, 0.8];
+        Gain::new(20.0 * 2_f64.log10(), false).apply(&mut samples, 1);
+        assert!((samples[0] - 0.5).abs() < 1e-9);
+        assert!((samples[1] + 0.5).abs() < 1e-9);
+        // Clamped instead of wrapping around
+        assert_eq!(samples[2], 1.0);
+
+        let mut samples = vec![0.25, -0.25];
+        Gain::new(0.0, false).apply(&mut samples, 2);
+        assert_eq!(samples, [0.25, -0.25]);
+    }
+
+    #[test]
+    fn test_gain_normalize() {
+        let mut gain = Gain::new(0.0, true);
+        let mut samples = sine(0.05);
+        gain.apply(&mut samples, 2);
+        assert!((rms(&samples) - NORMALIZE_TARGET_RMS).abs() < 1e-3);
+
+        // Silence is left silent
+        let mut silence = vec![0.0; 200];
+        gain.apply(&mut silence, 2);
+        assert!(silence.iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn test_gain_smoothed() {
+        let mut gain = Gain::new(0.0, true);
+        let mut quiet = sine(0.05);
+        gain.apply(&mut quiet, 2);
+        let quiet_gain = gain.level.unwrap();
+
+        // A loud chunk starts at the gain of the quiet one and only moves part of the way
+        let mut loud = sine(0.5);
+        let first = loud[2];
+        gain.apply(&mut loud, 2);
+        assert!((loud[2] / first - quiet_gain).abs() < 0.1);
+        let target = normalize_gain(&sine(0.5)).unwrap();
+        let level = gain.level.unwrap();
+        assert!(level < quiet_gain && level > target);
+    }
+
+    #[test]
+    fn test_audio_queue_drop_oldest() {
+        let (tx, rx) = audio_queue(2, OverflowPolicy::DropOldest);
+        for i in 0..4 {
+            tx.send(vec![i]).unwrap();
+        }
+
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(vec![2]));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(vec![3]));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Err(RecvTimeoutError::Timeout));
+
+        drop(tx);
+        assert_eq!(
+            rx.recv_timeout(TIMEOUT),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_audio_queue_block() {
+        let (tx, rx) = audio_queue(2, OverflowPolicy::Block);
+        tx.send(vec![0]).unwrap();
+        tx.send(vec![1]).unwrap();
+
+        // The full queue makes the sender wait
+        let handle = std::thread::spawn(move || tx.send(vec![2]));
+        std::thread::sleep(TIMEOUT);
+        assert!(!handle.is_finished());
+
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(vec![0]));
+        assert!(handle.join().unwrap().is_ok());
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(vec![1]));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(vec![2]));
+        assert_eq!(
+            rx.recv_timeout(TIMEOUT),
+            Err(RecvTimeoutError::Disconnected)
+        );
+
+        // The whole path from the decoding to the queue waits for a slow consumer
+        let path = temp_path("backpressure.mp3");
+        write_mp3(&path, &[(0.5, 128)]);
+        let (handle, rx) = stream_file(&path, 1000, OverflowPolicy::Block);
+        handle.join().unwrap();
+        let num_pieces = drain(&rx);
+        assert!(num_pieces > 2);
+
+        let (handle, rx) = stream_file(&path, 2, OverflowPolicy::Block);
+        // Twice the time the audio takes
+        std::thread::sleep(Duration::from_secs(1));
+        assert!(!handle.is_finished());
+        assert_eq!(rx.queue.state.lock().unwrap().pieces.len(), 2);
+        // No audio is lost
+        assert_eq!(drain(&rx), num_pieces);
+        handle.join().unwrap();
+
+        // Dropping the oldest audio, the decoding goes on without the consumer
+        let (handle, rx) = stream_file(&path, 2, OverflowPolicy::DropOldest);
+        handle.join().unwrap();
+        assert_eq!(drain(&rx), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_audio_queue_receiver_dropped() {
+        let (tx, rx) = audio_queue(1, OverflowPolicy::Block);
+        tx.send(vec![0]).unwrap();
+
+        let handle = std::thread::spawn(move || tx.send(vec![1]));
+        std::thread::sleep(TIMEOUT);
+        drop(rx);
+        assert!(handle.join().unwrap().is_err());
+    }
+}
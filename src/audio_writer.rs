@@ -0,0 +1,265 @@
+//!
+//! Writes the received audio to a file (as it is received or decoded to WAV).
+//!
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::mpsc::{channel, Receiver as MpscReceiver, Sender as MpscSender};
+// ---
+use hound::{SampleFormat, WavSpec, WavWriter};
+use minimp3::{Decoder, Frame};
+// ---
+#[allow(unused_imports)]
+use hab::{debug, error, info, log_input, trace, warn};
+// ---
+use crate::codec::{OpusStreamDecoder, OPUS_SAMPLE_RATE};
+use crate::config::AudioCodec;
+
+///
+/// Appends the received pieces of audio to the file in a background thread. If the path ends
+/// with `.wav`, the audio is decoded and stored as 16-bit PCM WAV; otherwise, the pieces
+/// are stored as they are received (i.e. a playable MP3 for the MP3 codec).
+///
+pub struct AudioWriter {
+    tx: MpscSender<Vec<u8>>,
+}
+
+impl AudioWriter {
+    pub fn new(path: &str, codec: AudioCodec) -> Self {
+        let (tx, rx) = channel();
+
+        let path = path.to_owned();
+        let is_wav = path.to_lowercase().ends_with(".wav");
+        std::thread::spawn(move || {
+            let res = match (is_wav, codec) {
+                (false, _) => write_raw(&path, rx),
+                (true, AudioCodec::Mp3) => write_wav_from_mp3(&path, rx),
+                (true, AudioCodec::Opus) => write_wav_from_opus(&path, rx),
+            };
+            if let Err(e) = res {
+                error!("Failed to write the audio to '{path}'! ERROR: {e}");
+            }
+        });
+
+        AudioWriter { tx }
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        if let Err(e) = self.tx.send(data.to_vec()) {
+            warn!("The audio writer is dead! ERROR: {e}");
+        }
+    }
+}
+
+fn write_raw(path: &str, rx: MpscReceiver<Vec<u8>>) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    while let Ok(piece) = rx.recv() {
+        file.write_all(&piece)?;
+        file.flush()?;
+    }
+    Ok(())
+}
+
+fn write_wav_from_mp3(path: &str, rx: MpscReceiver<Vec<u8>>) -> io::Result<()> {
+    let mut decoder = Decoder::new(ChannelReader::new(rx));
+    let mut writer = None;
+
+    loop {
+        match decoder.next_frame() {
+            Ok(Frame {
+                data,
+                sample_rate,
+                channels,
+                ..
+            }) => {
+                // The WAV format is given by the first decoded frame
+                if writer.is_none() {
+                    writer = Some(create_wav(path, channels as u16, sample_rate as u32)?);
+                }
+                write_samples(writer.as_mut().unwrap(), data.into_iter())?;
+            }
+            Err(minimp3::Error::Eof) => return Ok(()),
+            Err(minimp3::Error::Io(e)) => return Err(e),
+            Err(e) => warn!("Error decoding MP3 frame: {e:?}"),
+        }
+    }
+}
+
+fn write_wav_from_opus(path: &str, rx: MpscReceiver<Vec<u8>>) -> io::Result<()> {
+    let mut decoder = OpusStreamDecoder::new();
+    let mut writer = create_wav(path, 2, OPUS_SAMPLE_RATE)?;
+
+    while let Ok(piece) = rx.recv() {
+        let samples = decoder.decode(&piece);
+        let samples = samples.into_iter().map(|x| (x * i16::MAX as f32) as i16);
+        write_samples(&mut writer, samples)?;
+    }
+    Ok(())
+}
+
+fn create_wav(
+    path: &str,
+    channels: u16,
+    sample_rate: u32,
+) -> io::Result<WavWriter<BufWriter<File>>> {
+    let spec = WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    WavWriter::create(path, spec).map_err(to_io_error)
+}
+
+fn write_samples(
+    writer: &mut WavWriter<BufWriter<File>>,
+    samples: impl Iterator<Item = i16>,
+) -> io::Result<()> {
+    for s in samples {
+        writer.write_sample(s).map_err(to_io_error)?;
+    }
+    // Keep the header up-to-date so that the file is valid even if we get killed
+    writer.flush().map_err(to_io_error)
+}
+
+fn to_io_error(e: hound::Error) -> io::Error {
+    match e {
+        hound::Error::IoError(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+/// A blocking reader of the pieces received over the channel (EOF once the sender is dropped).
+pub struct ChannelReader {
+    rx: MpscReceiver<Vec<u8>>,
+    piece: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    pub fn new(rx: MpscReceiver<Vec<u8>>) -> Self {
+        ChannelReader {
+            rx,
+            piece: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.piece.len() {
+            match self.rx.recv() {
+                Ok(x) => {
+                    self.piece = x;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.piece.len() - self.pos);
+        buf[..n].copy_from_slice(&self.piece[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::OpusStreamEncoder;
+    use hound::WavReader;
+    use std::path::{Path, PathBuf};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("audibro-{}-{name}", std::process::id()))
+    }
+
+    /// Passes the pieces to the writer as they would be received and waits for it to finish.
+    fn write(
+        writer: fn(&str, MpscReceiver<Vec<u8>>) -> io::Result<()>,
+        path: &Path,
+        pieces: Vec<Vec<u8>>,
+    ) {
+        let (tx, rx) = channel();
+        for piece in pieces {
+            tx.send(piece).unwrap();
+        }
+        drop(tx);
+        writer(path.to_str().unwrap(), rx).unwrap();
+    }
+
+    #[test]
+    fn test_write_raw() {
+        let path = temp_path("received.mp3");
+        _ = std::fs::remove_file(&path);
+
+        // The pieces are stored as they are, one after another
+        let pieces = vec![b"first".to_vec(), vec![], b"second".to_vec()];
+        write(write_raw, &path, pieces);
+        assert_eq!(std::fs::read(&path).unwrap(), b"firstsecond");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_wav_from_opus() {
+        let path = temp_path("received-opus.wav");
+
+        // One second of the stereo sine in pieces of 100ms
+        let mut encoder = OpusStreamEncoder::new(64);
+        let pieces: Vec<_> = (0..10)
+            .map(|i| {
+                let samples: Vec<_> = (i * 4800..(i + 1) * 4800)
+                    .map(|x| 0.5 * (x as f64 * 0.05).sin())
+                    .flat_map(|x| [x, x])
+                    .collect();
+                encoder.encode(&samples, OPUS_SAMPLE_RATE)
+            })
+            .collect();
+        write(write_wav_from_opus, &path, pieces);
+
+        let reader = WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        assert_eq!(spec.channels, 2);
+        assert_eq!(spec.sample_rate, OPUS_SAMPLE_RATE);
+        assert_eq!(spec.bits_per_sample, 16);
+        assert_eq!(spec.sample_format, SampleFormat::Int);
+        // All the frames of both channels are there
+        assert_eq!(reader.len(), 2 * OPUS_SAMPLE_RATE);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_wav_from_mp3() {
+        let path = temp_path("received-mp3.wav");
+        let mp3 = std::fs::read("data/fun-punk-opener.mp3").unwrap();
+        let mp3 = &mp3[..64 * 1024];
+
+        // The frames split across the pieces are decoded the same as the whole file
+        let mut decoder = Decoder::new(mp3);
+        let mut expected = vec![];
+        while let Ok(frame) = decoder.next_frame() {
+            expected.push(frame);
+        }
+        write(
+            write_wav_from_mp3,
+            &path,
+            mp3.chunks(1000).map(|x| x.to_vec()).collect(),
+        );
+
+        let mut reader = WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        assert_eq!(spec.channels, expected[0].channels as u16);
+        assert_eq!(spec.sample_rate, expected[0].sample_rate as u32);
+        assert_eq!(spec.bits_per_sample, 16);
+        let samples: Vec<i16> = reader.samples().map(|x| x.unwrap()).collect();
+        let expected: Vec<i16> = expected.into_iter().flat_map(|x| x.data).collect();
+        assert!(!samples.is_empty());
+        assert_eq!(samples, expected);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
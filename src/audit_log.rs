@@ -0,0 +1,93 @@
+//!
+//! An append-only audit record of the authentication of every received block.
+//!
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+// ---
+use hab::common::MessageAuthentication;
+#[allow(unused_imports)]
+use hab::{debug, error, info, log_input, trace, warn};
+
+///
+/// Appends one CSV line per received block to the file:
+///
+/// `<timestamp>;<seq>;<outcome>;<size>;<sha256>`
+///
+/// where the outcome is `verified`, `certified` or `unverified`. The key that verified
+/// the block is not recorded, hab only passes the (local) petnames of its sender along.
+/// Each line is written by a single unbuffered write, so the records written so far
+/// survive a crash of the receiver.
+///
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    pub fn new(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { file })
+    }
+
+    pub fn record(
+        &mut self,
+        seq: u64,
+        authentication: &MessageAuthentication,
+        size: usize,
+        hash: &str,
+    ) {
+        let outcome = match authentication {
+            MessageAuthentication::Authenticated(_) => "verified",
+            MessageAuthentication::Certified(_) => "certified",
+            MessageAuthentication::Unverified => "unverified",
+        };
+        let line = format!(
+            "{};{seq};{outcome};{size};{hash}\n",
+            chrono::Local::now().to_rfc3339()
+        );
+
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            error!("Failed to write the audit log! ERROR: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hab::utils;
+
+    #[test]
+    fn test_audit_log() {
+        let path = std::env::temp_dir().join(format!("audibro-{}-audit.log", std::process::id()));
+        let blocks: [&[u8]; 3] = [b"first", b"second", b""];
+
+        let mut audit_log = AuditLog::new(path.to_str().unwrap()).unwrap();
+        for (seq, block) in blocks.iter().enumerate() {
+            let hash = utils::sha2_256_str(block);
+            audit_log.record(
+                seq as u64,
+                &MessageAuthentication::Unverified,
+                block.len(),
+                &hash,
+            );
+        }
+
+        // Written right away (without dropping the log)
+        let records = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<_> = records.lines().collect();
+        assert_eq!(records.len(), blocks.len());
+        for (seq, (record, block)) in records.iter().zip(blocks).enumerate() {
+            let fields: Vec<_> = record.split(';').collect();
+            assert_eq!(fields.len(), 5, "{record}");
+            assert!(chrono::DateTime::parse_from_rfc3339(fields[0]).is_ok());
+            assert_eq!(fields[1], seq.to_string());
+            assert_eq!(fields[2], "unverified");
+            assert_eq!(fields[3], block.len().to_string());
+            assert_eq!(fields[4], utils::sha2_256_str(block));
+        }
+
+        drop(audit_log);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+//!
+//! The Opus codec path for the broadcasted audio (the MP3 path lives in `audio_source`).
+//!
+//! One broadcasted piece carries a sequence of Opus packets, each prefixed with its
+//! length as a big-endian `u16`.
+//!
+
+use opus::{Application, Bitrate, Channels};
+// ---
+#[allow(unused_imports)]
+use hab::{debug, error, info, log_input, trace, warn};
+
+/// The sample rate the Opus codec runs at (all the input is resampled to it).
+pub const OPUS_SAMPLE_RATE: u32 = 48_000;
+/// Number of samples per channel in one Opus frame (20ms @ 48kHz).
+const OPUS_FRAME_SIZE: usize = 960;
+/// Maximum number of samples per channel one Opus packet can decode into (120ms @ 48kHz).
+const OPUS_MAX_FRAME_SIZE: usize = 5760;
+/// Maximum size of one encoded Opus packet.
+const OPUS_MAX_PACKET_SIZE: usize = 4000;
+
+/// Encodes a continuous stereo stream into Opus packets.
+pub struct OpusStreamEncoder {
+    encoder: opus::Encoder,
+    resampler: Resampler,
+    /// Interleaved stereo samples that did not fill a whole frame yet.
+    pending: Vec<f32>,
+}
+
+impl OpusStreamEncoder {
+    pub fn new(bitrate_kbps: u16) -> Self {
+        let mut encoder =
+            opus::Encoder::new(OPUS_SAMPLE_RATE, Channels::Stereo, Application::Audio)
+                .expect("Failed to create the Opus encoder");
+        encoder
+            .set_bitrate(Bitrate::Bits(bitrate_kbps as i32 * 1000))
+            .expect("Failed to set the Opus bitrate");
+        OpusStreamEncoder {
+            encoder,
+            resampler: Resampler::new(OPUS_SAMPLE_RATE),
+            pending: vec![],
+        }
+    }
+
+    ///
+    /// Encodes the interleaved stereo samples sampled at `sample_rate` into length-prefixed
+    /// Opus packets. Samples not filling a whole frame are kept for the next call.
+    ///
+    pub fn encode(&mut self, stereo: &[f64], sample_rate: u32) -> Vec<u8> {
+        let resampled = self.resampler.process(stereo, sample_rate);
+        self.pending.extend(resampled.into_iter().map(|x| x as f32));
+
+        let frame_len = OPUS_FRAME_SIZE * 2;
+        let mut packet = [0_u8; OPUS_MAX_PACKET_SIZE];
+        let mut out = vec![];
+        let mut consumed = 0;
+        while self.pending.len() - consumed >= frame_len {
+            let frame = &self.pending[consumed..consumed + frame_len];
+            let size = self
+                .encoder
+                .encode_float(frame, &mut packet)
+                .expect("Failed to encode the Opus frame");
+            out.extend_from_slice(&(size as u16).to_be_bytes());
+            out.extend_from_slice(&packet[..size]);
+            consumed += frame_len;
+        }
+        self.pending.drain(..consumed);
+
+        out
+    }
+}
+
+/// Decodes the pieces produced by `OpusStreamEncoder` back to PCM.
+pub struct OpusStreamDecoder {
+    decoder: opus::Decoder,
+}
+
+impl Default for OpusStreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpusStreamDecoder {
+    pub fn new() -> Self {
+        let decoder = opus::Decoder::new(OPUS_SAMPLE_RATE, Channels::Stereo)
+            .expect("Failed to create the Opus decoder");
+        OpusStreamDecoder { decoder }
+    }
+
+    ///
+    /// Decodes the length-prefixed Opus packets into interleaved stereo samples
+    /// at `OPUS_SAMPLE_RATE`. Malformed packets are skipped.
+    ///
+    pub fn decode(&mut self, data: &[u8]) -> Vec<f32> {
+        let mut frame = vec![0.0_f32; OPUS_MAX_FRAME_SIZE * 2];
+        let mut out = vec![];
+        let mut pos = 0;
+        while pos + 2 <= data.len() {
+            let size = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
+            pos += 2;
+            if pos + size > data.len() {
+                warn!("Truncated Opus packet, dropping the rest of the piece.");
+                break;
+            }
+
+            match self
+                .decoder
+                .decode_float(&data[pos..pos + size], &mut frame, false)
+            {
+                Ok(num_samples) => out.extend_from_slice(&frame[..num_samples * 2]),
+                Err(e) => warn!("Failed to decode the Opus packet! ERROR: {e}"),
+            }
+            pos += size;
+        }
+
+        out
+    }
+}
+
+///
+/// Resamples a continuous interleaved stereo stream to a fixed rate (the input rate may change
+/// between the calls, which starts it over). The samples are interpolated linearly; when
+/// downsampling, they are low-pass filtered first so that the frequencies above the new
+/// Nyquist frequency don't alias. Both the interpolation and the filter continue across
+/// the calls, so the chunks join without clicks.
+///
+pub struct Resampler {
+    to_rate: u32,
+    from_rate: u32,
+    /// The position of the next output frame in the input frames (counted from `last`).
+    pos: f64,
+    /// The last input frame of the previous call.
+    last: Option<[f64; 2]>,
+    /// The anti-aliasing filter (empty if not downsampling).
+    lowpass: Vec<LowPass>,
+}
+
+impl Resampler {
+    pub fn new(to_rate: u32) -> Self {
+        Resampler {
+            to_rate,
+            from_rate: to_rate,
+            pos: 0.0,
+            last: None,
+            lowpass: vec![],
+        }
+    }
+
+    /// Resamples the interleaved stereo samples sampled at `from_rate`.
+    pub fn process(&mut self, stereo: &[f64], from_rate: u32) -> Vec<f64> {
+        if from_rate != self.from_rate {
+            *self = Resampler::new(self.to_rate);
+            self.from_rate = from_rate;
+            if from_rate > self.to_rate {
+                // A 4th-order Butterworth filter as a cascade of two biquads
+                let cutoff = LOWPASS_CUTOFF * self.to_rate as f64 / 2.0;
+                self.lowpass = [0.5412, 1.3066]
+                    .iter()
+                    .map(|q| LowPass::new(cutoff, from_rate as f64, *q))
+                    .collect();
+            }
+        }
+        let stereo = &stereo[..stereo.len() - stereo.len() % 2];
+        if from_rate == self.to_rate {
+            return stereo.to_vec();
+        }
+
+        let mut frames: Vec<[f64; 2]> = self.last.into_iter().collect();
+        for frame in stereo.chunks_exact(2) {
+            let mut frame = [frame[0], frame[1]];
+            for filter in &mut self.lowpass {
+                for (ch, x) in frame.iter_mut().enumerate() {
+                    *x = filter.process(*x, ch);
+                }
+            }
+            frames.push(frame);
+        }
+        let last = match frames.last() {
+            Some(x) => *x,
+            None => return vec![],
+        };
+
+        let step = from_rate as f64 / self.to_rate as f64;
+        let end = (frames.len() - 1) as f64;
+        let mut out = Vec::with_capacity((end / step) as usize * 2 + 2);
+        while self.pos < end {
+            let idx = self.pos as usize;
+            let frac = self.pos - idx as f64;
+            let (a, b) = (frames[idx], frames[idx + 1]);
+            out.extend(a.iter().zip(&b).map(|(a, b)| a + (b - a) * frac));
+            self.pos += step;
+        }
+        // The last frame is where the next call continues from
+        self.pos -= end;
+        self.last = Some(last);
+
+        out
+    }
+}
+
+/// The cutoff of the anti-aliasing filter (relative to the new Nyquist frequency).
+const LOWPASS_CUTOFF: f64 = 0.9;
+
+/// A biquad low-pass filter of the interleaved stereo samples (the RBJ cookbook one).
+struct LowPass {
+    b: [f64; 3],
+    a: [f64; 2],
+    /// The state of the transposed direct form II per channel.
+    z: [[f64; 2]; 2],
+}
+
+impl LowPass {
+    fn new(cutoff: f64, sample_rate: f64, q: f64) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * cutoff / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        LowPass {
+            b: [
+                (1.0 - cos) / 2.0 / a0,
+                (1.0 - cos) / a0,
+                (1.0 - cos) / 2.0 / a0,
+            ],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            z: [[0.0; 2]; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64, ch: usize) -> f64 {
+        let z = &mut self.z[ch];
+        let y = self.b[0] * x + z[0];
+        z[0] = self.b[1] * x - self.a[0] * y + z[1];
+        z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    /// An interleaved stereo sine of `freq` Hz sampled at `rate` (`frames` frames from `start`).
+    fn sine(freq: f64, rate: u32, start: usize, frames: usize) -> Vec<f64> {
+        (start..start + frames)
+            .map(|i| (2.0 * PI * freq * i as f64 / rate as f64).sin())
+            .flat_map(|x| [x, x])
+            .collect()
+    }
+
+    /// Resamples the stream in chunks of various sizes.
+    fn resample(stereo: &[f64], from_rate: u32, to_rate: u32) -> Vec<f64> {
+        let mut resampler = Resampler::new(to_rate);
+        let mut out = vec![];
+        for (i, chunk) in stereo.chunks(2 * 1000).enumerate() {
+            // Split it unevenly (the odd sample stays with the frame)
+            let (a, b) = chunk.split_at((i * 2 * 37) % chunk.len() / 2 * 2);
+            out.extend(resampler.process(a, from_rate));
+            out.extend(resampler.process(b, from_rate));
+        }
+        out
+    }
+
+    fn rms(samples: &[f64]) -> f64 {
+        (samples.iter().map(|x| x * x).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn test_opus_round_trip() {
+        let input: Vec<f64> = sine(440.0, OPUS_SAMPLE_RATE, 0, 48_000)
+            .into_iter()
+            .map(|x| 0.5 * x)
+            .collect();
+        let encoded = OpusStreamEncoder::new(128).encode(&input, OPUS_SAMPLE_RATE);
+
+        // A whole number of length-prefixed packets, one per frame
+        let mut num_packets = 0;
+        let mut pos = 0;
+        while pos < encoded.len() {
+            pos += 2 + u16::from_be_bytes([encoded[pos], encoded[pos + 1]]) as usize;
+            num_packets += 1;
+        }
+        assert_eq!(pos, encoded.len());
+        assert_eq!(num_packets, 48_000 / OPUS_FRAME_SIZE);
+
+        let decoded = OpusStreamDecoder::new().decode(&encoded);
+        assert_eq!(decoded.len(), input.len());
+
+        // The codec delays the signal a bit, so compare the loudness once it settles
+        let settled: Vec<f64> = decoded[2 * 4800..].iter().map(|x| *x as f64).collect();
+        let expected = rms(&input[2 * 4800..]);
+        assert!(
+            (rms(&settled) - expected).abs() < 0.05 * expected,
+            "The RMS {} differs from {expected}!",
+            rms(&settled)
+        );
+    }
+
+    #[test]
+    fn test_opus_framing() {
+        let mut encoder = OpusStreamEncoder::new(64);
+
+        // Less than a frame is kept for the next call
+        let first = sine(440.0, OPUS_SAMPLE_RATE, 0, 500);
+        assert!(encoder.encode(&first, OPUS_SAMPLE_RATE).is_empty());
+        let second = sine(440.0, OPUS_SAMPLE_RATE, 500, 500);
+        let encoded = encoder.encode(&second, OPUS_SAMPLE_RATE);
+        assert_eq!(
+            OpusStreamDecoder::new().decode(&encoded).len(),
+            2 * OPUS_FRAME_SIZE
+        );
+
+        // A truncated packet is dropped
+        let truncated = &encoded[..encoded.len() - 1];
+        assert!(OpusStreamDecoder::new().decode(truncated).is_empty());
+    }
+
+    #[test]
+    fn test_resampler_length() {
+        let out = resample(&sine(1000.0, 44_100, 0, 44_100), 44_100, 48_000);
+        assert_eq!(out.len() % 2, 0);
+        assert!((out.len() as i64 / 2 - 48_000).abs() <= 2);
+
+        let out = resample(&sine(1000.0, 48_000, 0, 48_000), 48_000, 44_100);
+        assert!((out.len() as i64 / 2 - 44_100).abs() <= 2);
+
+        let mut resampler = Resampler::new(48_000);
+        assert_eq!(resampler.process(&[0.1, 0.2, 0.3], 48_000), [0.1, 0.2]);
+    }
+
+    #[test]
+    fn test_resampler_sine() {
+        let out = resample(&sine(1000.0, 44_100, 0, 44_100), 44_100, 48_000);
+
+        // The output is the same sine at the new rate (with no jumps between the chunks)
+        let expected = sine(1000.0, 48_000, 0, out.len() / 2);
+        let max_error = out
+            .iter()
+            .zip(&expected)
+            .fold(0.0_f64, |acc, (x, y)| acc.max((x - y).abs()));
+        assert!(max_error < 0.01, "The error {max_error} is too large!");
+    }
+
+    #[test]
+    fn test_resampler_lowpass() {
+        // Above the Nyquist frequency of the output, it would alias to 1kHz
+        let out = resample(&sine(15_000.0, 48_000, 0, 48_000), 48_000, 16_000);
+        let aliased = rms(&out[2 * 1000..]);
+        assert!(aliased < 0.1, "The aliased RMS {aliased} is too large!");
+    }
+}
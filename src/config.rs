@@ -2,16 +2,22 @@
 //! General static config file where you can tune the desired protocol paramters.
 //!
 
+use std::ops::RangeInclusive;
 // ---
 use cfg_if::cfg_if;
+#[cfg(feature = "cli")]
 use clap::Parser;
 use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
 // ---
 use hab::{utils, HorstSigScheme};
 // ---
 use crate::config;
 
 pub const WAITING_FOR_DATA: &str = "...waiting for data...";
+pub const STREAM_ENDED: &str = "STREAM ENDED";
+/// A payload of the signed block the sender broadcasts when it stops.
+pub const END_OF_STREAM: &[u8] = b"\0AUDIBRO:END-OF-STREAM\0";
 // pub const AUTH_LABEL: &str = "Authenticated";
 // pub const CART_LABEL: &str = "Certified";
 // pub const UNVER_LABEL: &str = "Unverified";
@@ -49,8 +55,6 @@ pub const SIM_INPUT_PERIOD: Option<std::time::Duration> = None;
 cfg_if! {
     // *** PRODUCTION ***
     if #[cfg(not(feature = "debug"))] {
-        use sha3::Sha3_256;
-
         /// Size of the hashes in a Merkle tree
         const N: usize = 256 / 8;
         /// Number of SK segments in signature
@@ -64,14 +68,9 @@ cfg_if! {
 
         /// Maximum number of secure signature per one key
         const KEY_CHARGES: usize = 16;
-
-        // --- Hash function ---
-        type HashFn = Sha3_256;
     }
     // *** DEBUG ***
     else {
-        use sha3::{Sha3_256};
-
         /// Size of the hashes in a Merkle tree
         const N: usize = 256 / 8;
         /// Number of SK segments in signature
@@ -85,9 +84,17 @@ cfg_if! {
         // --- Random generators ---
         /// A seedable CSPRNG used for number generation
         type CsPrng = ChaCha20Rng;
+    }
+}
 
-        // --- Hash functions ---
-        type HashFn = Sha3_256;
+// --- Hash function (selected by the `hash-*` features, its output must be `N` bytes) ---
+cfg_if! {
+    if #[cfg(feature = "hash-blake3")] {
+        type HashFn = blake3::Hasher;
+    } else if #[cfg(feature = "hash-sha2")] {
+        type HashFn = sha2::Sha256;
+    } else {
+        type HashFn = sha3::Sha3_256;
     }
 }
 
@@ -101,17 +108,92 @@ pub type SignerInst = HorstSigScheme<N, K, TAU, { TAU + 1 }, T, KEY_CHARGES, CsP
 // The clap config for command line arguments.
 // ***
 
+/// Bitrates (in kbps) supported by the LAME MP3 encoder.
+pub const MP3_BITRATES: &[u16] = &[
+    8, 16, 24, 32, 40, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+
+/// Bitrates (in kbps) supported by the Opus encoder.
+pub const OPUS_BITRATES: RangeInclusive<u16> = 6..=510;
+
 /// Modes in which the progarm can operate.
+#[cfg(feature = "cli")]
 #[derive(clap::ValueEnum, Clone, Debug)]
 pub enum ProgramMode {
     /// The broadcaster of the data.
     Sender,
     /// The subscriber to the broadcasters.
     Receiver,
+    /// The sender & receiver in one process connected directly (without UDP).
+    Loopback,
+}
+
+/// Formats of the log records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line (with `ts`, `level`, `tag` and `message` fields).
+    Json,
+}
+
+/// Audio codecs that the broadcasted audio can be encoded with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum AudioCodec {
+    /// MP3 via LAME (MP3 files are streamed as they are).
+    Mp3,
+    /// Opus (everything is re-encoded at 48kHz; lower bitrate).
+    Opus,
+}
+
+impl AudioCodec {
+    /// The bitrate (in kbps) the audio is encoded at unless `--audio-bitrate` is set.
+    pub fn default_bitrate(&self) -> u16 {
+        match self {
+            AudioCodec::Mp3 => 320,
+            AudioCodec::Opus => 64,
+        }
+    }
+
+    /// Checks that the encoder of the codec supports the bitrate (in kbps).
+    pub fn check_bitrate(&self, kbps: u16) -> Result<(), String> {
+        match self {
+            AudioCodec::Mp3 if !MP3_BITRATES.contains(&kbps) => Err(format!(
+                "{kbps}kbps is not a valid MP3 bitrate; use one of {MP3_BITRATES:?}"
+            )),
+            AudioCodec::Opus if !OPUS_BITRATES.contains(&kbps) => Err(format!(
+                "{kbps}kbps is not a valid Opus bitrate; use one in {OPUS_BITRATES:?}"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What the receiver does with the received audio while the playback is paused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum PauseMode {
+    /// Keep it and play it once resumed.
+    Buffer,
+    /// Throw it away (resumes at the live position).
+    Drop,
+}
+
+/// What the sender does with the captured audio when the broadcast can't keep up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum OverflowPolicy {
+    /// Wait until the broadcast catches up (the capture stalls).
+    Block,
+    /// Throw away the oldest audio waiting for the broadcast (keeps the latency bounded).
+    DropOldest,
 }
 
 /// Define the CLI.
-#[derive(Parser, Debug)]
+#[cfg(feature = "cli")]
+#[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
     // --- required ---
@@ -131,7 +213,8 @@ pub struct Args {
     /// Seed used for the CSPRNG.
     #[clap(short, long, default_value_t = 42)]
     pub seed: u64,
-    /// A desired number of key layers to use (for sender only)
+    /// Unused, only kept so that the existing scripts still work (the sender gets one key layer
+    /// per row of `key_dist` in the config file).
     #[clap(long, default_value_t = 8)]
     pub layers: usize,
     /// A number of keys to certify forward (and backward).
@@ -173,33 +256,377 @@ pub struct Args {
     /// A directory with MP3 files.
     #[clap(short, long, default_value = "../../data/")]
     pub data_dir: String,
+    /// If set, the sender broadcasts the contents of this file (or named pipe) instead of
+    /// the lines of the standard input (chunked by `--max-piece-size`).
+    #[clap(long)]
+    pub input: Option<String>,
+    /// An HTTP/HTTPS URL of a remote MP3 stream to offer for broadcasting (can be repeated).
+    #[clap(long)]
+    pub stream_url: Vec<String>,
+    /// If set, the playlists are played in a random order (seeded by `--seed`).
+    #[clap(long, action)]
+    pub shuffle: bool,
+    /// If set, the playlist of the TUI starts over once it ends (the autoplay always does).
+    #[clap(long, action)]
+    pub repeat: bool,
+    /// If set, the sender plays the MP3 files from this directory on a loop (without the TUI).
+    #[clap(long, conflicts_with = "tui")]
+    pub autoplay: Option<String>,
+    /// A (part of the) name of the input device to capture the microphone from (the default if not set).
+    #[clap(long)]
+    pub input_device: Option<String>,
+    /// A maximum number of the captured audio pieces waiting for the broadcast.
+    #[clap(long, default_value_t = 4)]
+    pub audio_queue_size: usize,
+    /// What to do with the captured audio once the queue for the broadcast is full.
+    #[clap(long, value_enum, default_value_t = OverflowPolicy::DropOldest)]
+    pub audio_overflow: OverflowPolicy,
     /// If set, the receiver will also re-distribute the messages.
     #[clap(long)]
     pub distribute: Option<String>,
+    /// If set, the receiver writes the received audio to this file (decoded if it ends with `.wav`).
+    #[clap(short, long)]
+    pub output: Option<String>,
+    /// If set, Prometheus metrics are served at `http://<metrics-addr>/metrics`.
+    #[clap(long)]
+    pub metrics_addr: Option<String>,
+    /// A format of the general log (written to `logs/output.json` in the JSON format).
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// A codec used for the broadcasted audio (must match on both sides).
+    #[clap(long, value_enum, default_value_t = AudioCodec::Mp3)]
+    pub codec: AudioCodec,
+    /// A bitrate of the broadcasted audio (in kbps; 320 for MP3 and 64 for Opus by default).
+    #[clap(long)]
+    pub audio_bitrate: Option<u16>,
+    /// A gain applied to the broadcasted audio (in dB).
+    #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    pub gain: f64,
+    /// If set, the loudness of the broadcasted audio is normalized.
+    #[clap(long, action)]
+    pub normalize: bool,
+    /// What the receiver TUI does with the received audio while paused.
+    #[clap(long, value_enum, default_value_t = PauseMode::Drop)]
+    pub pause_mode: PauseMode,
+    /// How much audio the receiver buffers before it starts playing (in milliseconds).
+    #[clap(long, default_value_t = 0)]
+    pub jitter_ms: u64,
+    /// A (part of the) name of the output device the receiver plays on (the default if not set).
+    #[clap(long)]
+    pub output_device: Option<String>,
+    /// If set, the receiver streams the authenticated audio at `http://<http-out>/stream`.
+    #[clap(long)]
+    pub http_out: Option<String>,
+    /// If set, the HTTP stream also includes the certified (not only the authenticated) audio.
+    #[clap(long, action)]
+    pub http_out_certified: bool,
+    /// If set, the receiver appends a record of the authentication of each block to this file.
+    #[clap(long)]
+    pub audit_log: Option<String>,
+    /// Another sender to receive from at the same time as `<addr>:<name>` (can be repeated).
+    #[clap(long, value_parser = parse_sender)]
+    pub source: Vec<(String, String)>,
+}
+
+/// Parses the `<addr>:<name>` of a sender (e.g. `127.0.0.1:5556:carol`).
+#[cfg(feature = "cli")]
+fn parse_sender(s: &str) -> Result<(String, String), String> {
+    match s.rsplit_once(':') {
+        // The address must have the port as well
+        Some((addr, name)) if addr.contains(':') && !name.is_empty() => {
+            Ok((addr.to_owned(), name.to_owned()))
+        }
+        _ => Err(format!(
+            "'{s}' is not a sender in the `<addr>:<name>` format"
+        )),
+    }
+}
+
+///
+/// The config file (`--config`) with the parameters of the sender.
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileConfig {
+    /// One row per key layer, hab creates as many layers as there are rows.
+    pub key_dist: Vec<Vec<usize>>,
+}
+
+impl FileConfig {
+    /// Reads, parses and validates the config file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let config_str = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read the config file '{path}': {e}"))?;
+        let config: FileConfig = toml::from_str(&config_str)
+            .map_err(|e| format!("Failed to parse the config file '{path}': {e}"))?;
+        config
+            .validate()
+            .map_err(|e| format!("Invalid config file '{path}': {e}"))?;
+        Ok(config)
+    }
+
+    ///
+    /// Checks that `key_dist` has at least one layer, that no row is empty and that
+    /// the weight of each layer (the first value in the row) is positive.
+    ///
+    pub fn validate(&self) -> Result<(), String> {
+        if self.key_dist.is_empty() {
+            return Err("`key_dist` has no rows, at least one key layer is needed!".into());
+        }
+
+        for (i, row) in self.key_dist.iter().enumerate() {
+            match row.first() {
+                None => return Err(format!("`key_dist` row {i} is empty!")),
+                Some(0) => {
+                    return Err(format!(
+                        "`key_dist` row {i} ({row:?}) has a zero weight, it must be positive!"
+                    ))
+                }
+                Some(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "cli")]
+impl Args {
+    ///
+    /// Checks the arguments that depend on each other. The receiver must send
+    /// the heartbeats more often than the sender forgets it (`--receiver-lifetime-s`),
+    /// otherwise its subscription flaps. The audio bitrate must suit the codec.
+    ///
+    pub fn validate(&self) -> Result<(), String> {
+        let sends = !matches!(self.mode, ProgramMode::Receiver);
+        if let (true, Some(kbps)) = (sends, self.audio_bitrate) {
+            self.codec.check_bitrate(kbps)?;
+        }
+
+        let receives = !matches!(self.mode, ProgramMode::Sender);
+        if receives && self.heartbeat_period_s >= self.receiver_lifetime_s {
+            return Err(format!(
+                "`--heartbeat-period-s` ({}s) must be shorter than `--receiver-lifetime-s` ({}s)!",
+                self.heartbeat_period_s, self.receiver_lifetime_s
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// One log record in the JSON log format.
+#[derive(Serialize)]
+struct JsonLogRecord<'a> {
+    ts: String,
+    level: &'a str,
+    tag: &'a str,
+    message: String,
+}
+
+///
+/// The tag of the log the record belongs to: its target if it is one of the tags,
+/// else (e.g. a module path) the general log.
+///
+fn log_tag(target: &str) -> &str {
+    match USED_LOG_TAGS.iter().find(|x| **x == target) {
+        Some(tag) => tag,
+        None => "output",
+    }
+}
+
+/// Formats the log record as one line in the given format.
+fn format_log_record(format: LogFormat, record: &log::Record) -> String {
+    match format {
+        LogFormat::Text => format!(
+            "[{}][{}] {}",
+            //chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+            chrono::Local::now().format("%H:%M:%S"),
+            record.level(),
+            record.args()
+        ),
+        LogFormat::Json => {
+            let json_record = JsonLogRecord {
+                ts: chrono::Local::now().to_rfc3339(),
+                level: record.level().as_str(),
+                tag: log_tag(record.target()),
+                message: record.args().to_string(),
+            };
+            serde_json::to_string(&json_record).expect("The log record should be serializable.")
+        }
+    }
 }
 
 ///
 /// Setups the logger so it ignores the debug & trace logs in the third-party libs.
+/// The JSON records go to `logs/output.json` instead of `logs/output.log`, because
+/// the tagged macros of hab write their text records to the `logs/<tag>.log` files.
 ///
-pub fn setup_logger() -> Result<(), fern::InitError> {
+pub fn setup_logger(format: LogFormat) -> Result<(), fern::InitError> {
     std::fs::create_dir_all(config::LOGS_DIR).expect("The logs directory should be created.");
+    let filename = match format {
+        LogFormat::Text => "output.log",
+        LogFormat::Json => "output.json",
+    };
 
     fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}][{}] {}",
-                //chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                chrono::Local::now().format("%H:%M:%S"),
-                record.level(),
-                message
-            ))
+        .format(move |out, _, record| {
+            out.finish(format_args!("{}", format_log_record(format, record)))
         })
         // Disable all by default
         .level(log::LevelFilter::Info) // TODO: This does now work properly
         // Allow for this module
         .level_for(utils::binary_name(), log::LevelFilter::Trace)
         //.chain(std::io::stdout())
-        .chain(fern::log_file(format!("{}/output.log", config::LOGS_DIR))?)
+        .chain(fern::log_file(format!("{}/{filename}", config::LOGS_DIR))?)
         .apply()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_config(key_dist: &str) -> FileConfig {
+        toml::from_str(&format!("key_dist = {key_dist}")).unwrap()
+    }
+
+    #[test]
+    fn test_file_config_valid() {
+        let config = file_config("[ [4, 100], [2, 50], [1, 0] ]");
+        assert_eq!(config.key_dist.len(), 3);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_file_config_wrong_length() {
+        assert!(file_config("[]").validate().is_err());
+        assert!(file_config("[ [4, 100], [] ]").validate().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn test_args_heartbeat_period() {
+        let args = |extra: &[&str]| {
+            Args::parse_from(["audibro"].iter().chain(extra).chain(&[
+                "receiver",
+                "127.0.0.1:5555",
+                "alice",
+            ]))
+        };
+        assert!(args(&[]).validate().is_ok());
+        assert!(args(&["--heartbeat-period-s=9"]).validate().is_ok());
+        assert!(args(&["--heartbeat-period-s=10"]).validate().is_err());
+        assert!(args(&["--heartbeat-period-s=5", "--receiver-lifetime-s=3"])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_hash_fn() {
+        use std::any::TypeId;
+
+        cfg_if! {
+            if #[cfg(feature = "hash-blake3")] {
+                let expected = TypeId::of::<blake3::Hasher>();
+            } else if #[cfg(feature = "hash-sha2")] {
+                let expected = TypeId::of::<sha2::Sha256>();
+            } else {
+                let expected = TypeId::of::<sha3::Sha3_256>();
+            }
+        }
+        assert_eq!(TypeId::of::<HashFn>(), expected);
+        assert_eq!(<HashFn as sha3::Digest>::output_size(), N);
+    }
+
+    #[test]
+    fn test_signer_round_trip() {
+        use hab::SignatureSchemeTrait;
+        use rand_chacha::rand_core::SeedableRng;
+
+        let key_pair = SignerInst::gen_key_pair(&mut CsPrng::seed_from_u64(42));
+        let signature = SignerInst::sign(b"Hello!", &key_pair.secret, &key_pair.public);
+        assert!(SignerInst::verify(b"Hello!", &signature, &key_pair.public));
+        assert!(!SignerInst::verify(b"Hello?", &signature, &key_pair.public));
+    }
+
+    #[test]
+    fn test_log_record_json() {
+        let line = format_log_record(
+            LogFormat::Json,
+            &log::Record::builder()
+                .args(format_args!("STATUS: {}", 42))
+                .level(log::Level::Info)
+                .target("receiver")
+                .build(),
+        );
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(json["ts"].is_string());
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["tag"], "receiver");
+        assert_eq!(json["message"], "STATUS: 42");
+
+        // The untagged records belong to the general log
+        let line = format_log_record(
+            LogFormat::Json,
+            &log::Record::builder()
+                .args(format_args!("Hello"))
+                .level(log::Level::Debug)
+                .target("audibro::sender")
+                .build(),
+        );
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["level"], "DEBUG");
+        assert_eq!(json["tag"], "output");
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn test_args_audio_bitrate() {
+        let args = |extra: &[&str]| {
+            Args::parse_from(["audibro"].iter().chain(extra).chain(&[
+                "sender",
+                "0.0.0.0:5555",
+                "alice",
+            ]))
+        };
+        assert!(args(&[]).validate().is_ok());
+        assert!(args(&["--audio-bitrate=128"]).validate().is_ok());
+        assert!(args(&["--audio-bitrate=100"]).validate().is_err());
+        assert!(args(&["--codec=opus", "--audio-bitrate=100"])
+            .validate()
+            .is_ok());
+        assert!(args(&["--codec=opus", "--audio-bitrate=5"])
+            .validate()
+            .is_err());
+        assert!(args(&["--codec=opus", "--audio-bitrate=600"])
+            .validate()
+            .is_err());
+
+        for codec in [AudioCodec::Mp3, AudioCodec::Opus] {
+            assert!(codec.check_bitrate(codec.default_bitrate()).is_ok());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn test_parse_sender() {
+        assert_eq!(
+            parse_sender("127.0.0.1:5556:carol"),
+            Ok(("127.0.0.1:5556".to_owned(), "carol".to_owned()))
+        );
+        assert_eq!(
+            parse_sender("[::1]:5556:carol"),
+            Ok(("[::1]:5556".to_owned(), "carol".to_owned()))
+        );
+        assert!(parse_sender("127.0.0.1:5556").is_err());
+        assert!(parse_sender("127.0.0.1:5556:").is_err());
+        assert!(parse_sender("carol").is_err());
+    }
+
+    #[test]
+    fn test_file_config_zero_weight() {
+        assert!(file_config("[ [4, 100], [0, 50], [1, 0] ]")
+            .validate()
+            .is_err());
+        assert!(file_config("[ [0, 0] ]").validate().is_err());
+    }
+}
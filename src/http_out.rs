@@ -0,0 +1,146 @@
+//!
+//! Serves the received audio over HTTP so that a browser (or VLC) can listen to it.
+//!
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver as MpscReceiver, Sender as MpscSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+// ---
+#[allow(unused_imports)]
+use hab::{debug, error, info, log_input, trace, warn};
+// ---
+use crate::codec::{OpusStreamDecoder, OPUS_SAMPLE_RATE};
+use crate::config::AudioCodec;
+
+/// The senders of the audio to the connected listeners.
+type Listeners = Arc<Mutex<Vec<MpscSender<Arc<Vec<u8>>>>>>;
+
+///
+/// Streams the pieces of audio passed to it to all the listeners connected at `/stream`.
+/// The MP3 audio is streamed as it is received; the Opus audio is decoded and streamed
+/// as an endless 16-bit PCM WAV (since the players can't play the raw Opus packets).
+///
+pub struct HttpOut {
+    tx: MpscSender<Vec<u8>>,
+}
+
+impl HttpOut {
+    pub fn new(addr: &str, codec: AudioCodec) -> Self {
+        let listener = TcpListener::bind(addr).expect("Failed to bind the HTTP output");
+        info!("Serving the received audio at http://{addr}/stream");
+
+        let listeners = Listeners::default();
+        let listeners_clone = listeners.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let (tx, rx) = channel();
+                        listeners_clone.lock().unwrap().push(tx);
+                        std::thread::spawn(move || {
+                            if let Err(e) = serve_listener(stream, codec, rx) {
+                                debug!("The HTTP listener has disconnected: {e}");
+                            }
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept the HTTP connection! ERROR: {e}"),
+                }
+            }
+        });
+
+        let (tx, rx) = channel();
+        std::thread::spawn(move || fan_out(codec, rx, listeners));
+
+        HttpOut { tx }
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        if let Err(e) = self.tx.send(data.to_vec()) {
+            warn!("The HTTP output is dead! ERROR: {e}");
+        }
+    }
+}
+
+/// Passes the received pieces (decoded if needed) on to all the connected listeners.
+fn fan_out(codec: AudioCodec, rx: MpscReceiver<Vec<u8>>, listeners: Listeners) {
+    let mut decoder = (codec == AudioCodec::Opus).then(OpusStreamDecoder::new);
+
+    while let Ok(piece) = rx.recv() {
+        let piece = match &mut decoder {
+            Some(decoder) => decoder
+                .decode(&piece)
+                .into_iter()
+                .flat_map(|x| ((x * i16::MAX as f32) as i16).to_le_bytes())
+                .collect(),
+            None => piece,
+        };
+
+        // The listeners that have disconnected are forgotten
+        let piece = Arc::new(piece);
+        listeners
+            .lock()
+            .unwrap()
+            .retain(|x| x.send(piece.clone()).is_ok());
+    }
+}
+
+fn serve_listener(
+    mut stream: TcpStream,
+    codec: AudioCodec,
+    rx: MpscReceiver<Arc<Vec<u8>>>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    if path != "/stream" {
+        write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?;
+        return stream.flush();
+    }
+
+    let content_type = match codec {
+        AudioCodec::Mp3 => "audio/mpeg",
+        AudioCodec::Opus => "audio/wav",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+    if codec == AudioCodec::Opus {
+        stream.write_all(&wav_header(2, OPUS_SAMPLE_RATE))?;
+    }
+    stream.flush()?;
+
+    while let Ok(piece) = rx.recv() {
+        stream.write_all(&piece)?;
+    }
+    Ok(())
+}
+
+///
+/// A header of a 16-bit PCM WAV of an unknown length (the sizes are set to the maximum,
+/// which the players treat as an endless stream).
+///
+fn wav_header(channels: u16, sample_rate: u32) -> Vec<u8> {
+    let block_align = channels * 2;
+    let mut header = vec![];
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16_u32.to_le_bytes());
+    header.extend_from_slice(&1_u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16_u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
@@ -0,0 +1,219 @@
+//!
+//! A reader of a remote (HTTP/HTTPS) audio stream that survives transient network errors.
+//!
+
+use std::io::{self, Read};
+use std::thread;
+use std::time::Duration;
+// ---
+#[allow(unused_imports)]
+use hab::{debug, error, info, log_input, trace, warn};
+
+/// How many times we try to reconnect before giving up on the stream.
+const MAX_RECONNECTS: usize = 5;
+/// A delay before the first reconnect attempt (doubled with each next attempt).
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+///
+/// Streams the body of the HTTP resource. When the connection breaks, it reconnects
+/// and resumes from the last read byte using a range request. If the server ignores
+/// the range, the already read part of a resource (of a known length) is skipped,
+/// while a live stream (of an unknown length) continues from where it is now.
+///
+pub struct HttpStream {
+    url: String,
+    reader: Box<dyn Read + Send + Sync>,
+    /// Number of bytes of the resource read so far.
+    offset: u64,
+    /// Number of reconnects since the last successful read.
+    reconnects: usize,
+}
+
+impl HttpStream {
+    pub fn open(url: &str) -> io::Result<Self> {
+        let reader = Self::connect(url, 0)?;
+        Ok(HttpStream {
+            url: url.to_owned(),
+            reader,
+            offset: 0,
+            reconnects: 0,
+        })
+    }
+
+    /// Returns true if the source location should be treated as an HTTP/HTTPS URL.
+    pub fn is_url(location: &str) -> bool {
+        location.starts_with("http://") || location.starts_with("https://")
+    }
+
+    fn connect(url: &str, offset: u64) -> io::Result<Box<dyn Read + Send + Sync>> {
+        let mut request = ureq::get(url);
+        if offset > 0 {
+            request = request.set("Range", &format!("bytes={offset}-"));
+        }
+
+        let response = match request.call() {
+            Ok(x) => x,
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        };
+        if offset == 0 {
+            return Ok(response.into_reader());
+        }
+
+        // The partial content must start right at the offset
+        if response.status() == 206 {
+            let range = response.header("Content-Range").unwrap_or_default();
+            if !range.starts_with(&format!("bytes {offset}-")) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The stream resumed at '{range}' instead of the byte {offset}!"),
+                ));
+            }
+            return Ok(response.into_reader());
+        }
+
+        // Else the whole resource is sent again
+        let live = response.header("Content-Length").is_none();
+        let mut reader = response.into_reader();
+        if live {
+            warn!("The stream '{url}' can't be resumed, continuing with its live part.");
+        } else {
+            warn!("The stream '{url}' can't be resumed, skipping the first {offset} bytes.");
+            let skipped = io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+            if skipped < offset {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("The stream ended at the byte {skipped} before the byte {offset}!"),
+                ));
+            }
+        }
+        Ok(reader)
+    }
+
+    fn reconnect(&mut self, err: io::Error) -> io::Result<()> {
+        if self.reconnects >= MAX_RECONNECTS {
+            return Err(err);
+        }
+
+        let delay = RECONNECT_DELAY * 2_u32.pow(self.reconnects as u32);
+        self.reconnects += 1;
+        warn!(
+            "Reconnecting to '{}' at byte {} in {}ms (attempt {}/{MAX_RECONNECTS})...",
+            self.url,
+            self.offset,
+            delay.as_millis(),
+            self.reconnects
+        );
+        thread::sleep(delay);
+
+        self.reader = Self::connect(&self.url, self.offset)?;
+        Ok(())
+    }
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.reader.read(buf) {
+                Ok(n) => {
+                    self.offset += n as u64;
+                    self.reconnects = 0;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+                Err(mut e) => {
+                    warn!("The stream '{}' broke! ERROR: {e}", self.url);
+                    // Failed connection attempts count towards the limit too
+                    loop {
+                        match self.reconnect(e) {
+                            Ok(()) => break,
+                            Err(err) if self.reconnects < MAX_RECONNECTS => e = err,
+                            Err(err) => return Err(err),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    ///
+    /// Serves the body on a local port, the first response breaks off in the middle of it.
+    /// Returns the URL and the ranges requested by the following requests.
+    ///
+    fn serve(body: Vec<u8>, supports_range: bool) -> (String, Arc<Mutex<Vec<Option<u64>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stream.mp3", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let ranges_clone = ranges.clone();
+
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8(request).unwrap().to_lowercase();
+                let range = request
+                    .split_once("range: bytes=")
+                    .and_then(|(_, x)| x.split_once('-'))
+                    .map(|(x, _)| x.parse::<u64>().unwrap());
+                if i > 0 {
+                    ranges_clone.lock().unwrap().push(range);
+                }
+
+                let len = body.len();
+                let (head, data) = match range {
+                    Some(from) if supports_range => (
+                        format!(
+                            "206 Partial Content\r\nContent-Range: bytes {from}-{}/{len}\r\nContent-Length: {}",
+                            len - 1,
+                            len - from as usize
+                        ),
+                        &body[from as usize..],
+                    ),
+                    // The first response ends halfway through
+                    _ if i == 0 => (format!("200 OK\r\nContent-Length: {len}"), &body[..len / 2]),
+                    _ => (format!("200 OK\r\nContent-Length: {len}"), &body[..]),
+                };
+                let head = format!("HTTP/1.1 {head}\r\nConnection: close\r\n\r\n");
+                _ = stream.write_all(head.as_bytes());
+                _ = stream.write_all(data);
+            }
+        });
+
+        (url, ranges)
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut body = vec![0; 64 * 1024];
+        File::open("data/fun-punk-opener.mp3")
+            .unwrap()
+            .read_exact(&mut body)
+            .unwrap();
+
+        for supports_range in [true, false] {
+            let (url, ranges) = serve(body.clone(), supports_range);
+            let mut stream = HttpStream::open(&url).unwrap();
+            let mut received = vec![];
+            stream.read_to_end(&mut received).unwrap();
+
+            // The bytes go on right where the broken connection ended (whether the range is supported or not)
+            assert!(
+                received == body,
+                "The resumed stream differs from the served one!"
+            );
+            assert_eq!(*ranges.lock().unwrap(), [Some(body.len() as u64 / 2)]);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//!
+//! AudiBro is an authenticated audio broadcasting on top of the HAB protocol.
+//!
+//! The protocol core (signing, verification & networking) lives in the `hab` crate and its
+//! public API is re-exported here. Programs that only need the authenticated channel can use
+//! just that; `AudiBroSender`/`AudiBroReceiver` add the audio sources, playback and the TUI.
+//!
+//! The cargo features select what is built on top of the core:
+//!
+//! * `audio` - `AudiBroSender`/`AudiBroReceiver` (with rodio, cpal, Opus, LAME & crossterm),
+//! * `cli` - the command line arguments (`config::Args`) and the binary (implies `audio`).
+//!
+//! Both are enabled by default; use `default-features = false` to get just the core.
+//!
+//! A minimal sign→verify round-trip with the sender & receiver connected by a channel
+//! instead of UDP:
+//!
+//! ```no_run
+//! use std::sync::mpsc::channel;
+//! use std::sync::{atomic::AtomicBool, Arc};
+//! use std::time::Duration;
+//!
+//! use audibro::{
+//!     MessageAuthentication, Receiver, ReceiverParams, ReceiverTrait, Sender, SenderParams,
+//!     SenderTrait, SignerInst,
+//! };
+//!
+//! let running = Arc::new(AtomicBool::new(true));
+//! let (tx, rx) = channel();
+//!
+//! let mut sender = Sender::<SignerInst>::new(SenderParams {
+//!     sender_addr: "127.0.0.1:0".into(),
+//!     running: running.clone(),
+//!     seed: 42,
+//!     id_filename: ".identity/sender.bin".into(),
+//!     datagram_size: 1500,
+//!     receiver_lifetime: Duration::from_secs(10),
+//!     pre_cert: 1,
+//!     max_piece_size: 1024 * 1024,
+//!     key_dist: vec![vec![4, 100], vec![2, 50], vec![1, 0]],
+//!     key_charges: None,
+//!     dgram_delay: Duration::ZERO,
+//!     alt_output: Some(tx),
+//! });
+//! let mut receiver = Receiver::<SignerInst>::new(ReceiverParams {
+//!     running,
+//!     target_addr: "127.0.0.1:0".into(),
+//!     target_name: "alice".into(),
+//!     id_filename: ".identity/receiver.bin".into(),
+//!     distribute: None,
+//!     heartbeat_period: Duration::from_secs(5),
+//!     delivery_delay: Duration::from_millis(100),
+//!     frag_timeout: Duration::from_secs(10),
+//!     dgram_delay: Duration::ZERO,
+//!     receiver_lifetime: Duration::from_secs(10),
+//!     deliver: true,
+//!     alt_input: Some(rx),
+//! });
+//!
+//! sender.broadcast(b"Hello!".to_vec()).unwrap();
+//! let block = receiver.receive().unwrap();
+//! assert_eq!(block.message, b"Hello!");
+//! assert!(!matches!(block.authentication, MessageAuthentication::Unverified));
+//! ```
+//!
+
+pub mod config;
+#[cfg(feature = "audio")]
+pub mod receiver;
+#[cfg(feature = "audio")]
+pub mod sender;
+// --- The internals of the sender & receiver ---
+#[cfg(feature = "audio")]
+pub(crate) mod audio_source;
+#[cfg(feature = "audio")]
+pub(crate) mod audio_writer;
+#[cfg(feature = "audio")]
+pub(crate) mod audit_log;
+#[cfg(feature = "audio")]
+pub(crate) mod codec;
+#[cfg(feature = "audio")]
+pub(crate) mod http_out;
+#[cfg(feature = "audio")]
+pub(crate) mod http_stream;
+#[cfg(feature = "audio")]
+pub(crate) mod metrics;
+#[cfg(feature = "audio")]
+pub(crate) mod player;
+#[cfg(feature = "audio")]
+pub(crate) mod tui;
+
+// --- The protocol core ---
+pub use hab;
+pub use hab::common::MessageAuthentication;
+pub use hab::{Receiver, ReceiverParams, ReceiverTrait, Sender, SenderParams, SenderTrait};
+// ---
+pub use config::SignerInst;
+#[cfg(feature = "audio")]
+pub use receiver::{AudiBroReceiver, AudiBroReceiverParams};
+#[cfg(feature = "audio")]
+pub use sender::{AudiBroSender, AudiBroSenderParams};
@@ -1,68 +1,44 @@
 //!
 //! <PROJECT_NAME> is an implementation of the hash-based authentication protocol for streamed data.
 //!
-mod audio_source;
-mod config;
-mod receiver;
-mod sender;
-mod sliding_buffer;
-mod tui;
-// ---
+//! The binary is only the CLI on top of the `audibro` library.
+//!
 use std::sync::{
     atomic::{AtomicBool, Ordering},
+    mpsc::{channel, Receiver as MpscReceiver, Sender as MpscSender},
     Arc,
 };
-use std::thread;
-use std::time::Duration;
 // ---
 use clap::Parser;
 #[allow(unused_imports)]
 use hab::{debug, error, info, log_input, trace, warn};
-use serde::{Deserialize, Serialize};
 // ---
-use crate::config::{Args, ProgramMode};
-use crate::receiver::{AudiBroReceiver, AudiBroReceiverParams};
-use crate::sender::{AudiBroSender, AudiBroSenderParams};
-
-fn run_sender(args: Args, running: Arc<AtomicBool>, file_config: FileConfig) {
+use audibro::config::{self, Args, FileConfig, ProgramMode};
+use audibro::{AudiBroReceiver, AudiBroReceiverParams, AudiBroSender, AudiBroSenderParams};
+
+fn run_sender(
+    args: Args,
+    running: Arc<AtomicBool>,
+    file_config: FileConfig,
+    alt_output: Option<MpscSender<Vec<u8>>>,
+) -> Result<(), String> {
     let sender_params = AudiBroSenderParams {
         running,
-        seed: args.seed,
-        layers: args.layers,
-        addr: args.addr,
-        key_charges: args.key_charges,
-        cert_interval: args.cert_interval,
-        max_piece_size: args.max_piece_size,
-        id_filepath: args.id_filepath,
-        dgram_size: args.dgram_size,
-        receiver_lifetime: Duration::from_secs(args.receiver_lifetime_s),
-        key_dist: file_config.key_dist,
-        dgram_delay: Duration::from_micros(args.dgram_delay_us),
-        tui: args.tui,
-        data_dir: args.data_dir,
+        alt_output,
+        ..AudiBroSenderParams::from_args(args, file_config.key_dist)
     };
     info!("Running a sender with {sender_params:#?}");
 
     let mut sender = AudiBroSender::new(sender_params);
 
-    sender.run();
+    sender.run()
 }
 
-fn run_receiver(args: Args, running: Arc<AtomicBool>) {
+fn run_receiver(args: Args, running: Arc<AtomicBool>, alt_input: Option<MpscReceiver<Vec<u8>>>) {
     let recv_params = AudiBroReceiverParams {
         running,
-        target_addr: args.addr,
-        target_name: args.target_name,
-        delivery_deadline: Duration::from_millis(args.delivery_deadline_ms),
-        heartbeat_period: Duration::from_secs(args.heartbeat_period_s),
-        frag_timeout: Duration::from_secs(args.frag_timeout_s),
-        id_filepath: args.id_filepath,
-        receiver_lifetime: Duration::from_secs(args.receiver_lifetime_s),
-        deliver: args.deliver,
-        dgram_delay: Duration::from_micros(args.dgram_delay_us),
-        tui: args.tui,
-        distribute: args.distribute,
-        alt_input: None,
+        alt_input,
+        ..AudiBroReceiverParams::from_args(args)
     };
     info!("Running a receiver with {recv_params:#?}");
 
@@ -71,6 +47,35 @@ fn run_receiver(args: Args, running: Arc<AtomicBool>) {
     receiver.run();
 }
 
+///
+/// Runs the sender reading the standard input and the receiver printing the results
+/// in one process, the datagrams are passed between them over a channel.
+///
+fn run_loopback(
+    args: Args,
+    running: Arc<AtomicBool>,
+    file_config: FileConfig,
+) -> Result<(), String> {
+    if args.tui {
+        warn!("The TUI is not available in the loopback mode, ignoring `--tui`.");
+    }
+    let (tx, rx) = channel();
+
+    let recv_args = Args {
+        tui: false,
+        // Both sides need their own identity
+        id_filepath: format!("{}.loopback", args.id_filepath),
+        // The metrics endpoint is served by the sender (the address can be bound only once)
+        metrics_addr: None,
+        ..args.clone()
+    };
+    let recv_running = running.clone();
+    std::thread::spawn(move || run_receiver(recv_args, recv_running, Some(rx)));
+
+    let send_args = Args { tui: false, ..args };
+    run_sender(send_args, running, file_config, Some(tx))
+}
+
 fn init_application() -> Arc<AtomicBool> {
     // Clear the directories before every launch
     _ = std::fs::remove_dir_all(config::INPUT_DBG_DIR);
@@ -88,9 +93,11 @@ fn init_application() -> Arc<AtomicBool> {
     let running_clone = running.clone();
 
     ctrlc::set_handler(move || {
-        running_clone.store(false, Ordering::Release);
-        thread::sleep(std::time::Duration::from_millis(100));
-        std::process::exit(0x01);
+        // The second Ctrl-C kills the program right away
+        if !running_clone.swap(false, Ordering::AcqRel) {
+            std::process::exit(0x01);
+        }
+        eprintln!("Shutting down (press Ctrl-C again to force it)...");
     })
     .expect("Error setting Ctrl-C handler");
 
@@ -103,27 +110,28 @@ fn init_application() -> Arc<AtomicBool> {
     running
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct FileConfig {
-    key_dist: Vec<Vec<usize>>,
-}
-
-fn main() {
-    if let Err(e) = config::setup_logger() {
-        panic!("Unable to initialize the logger!\nERROR: {}", e);
-    }
-
+fn main() -> Result<(), String> {
     // Override with cmd args
     // TODO
     let args = Args::parse();
-    let running = init_application();
+    args.validate()?;
 
-    let config_str = std::fs::read_to_string(&args.config).expect("Failed to read config file");
-    let config: FileConfig = toml::from_str(&config_str).expect("Failed to parse config file");
+    if let Err(e) = config::setup_logger(args.log_format) {
+        panic!("Unable to initialize the logger!\nERROR: {}", e);
+    }
+    let running = init_application();
 
     // Sender mode
     match args.mode {
-        ProgramMode::Sender => run_sender(args, running, config),
-        ProgramMode::Receiver => run_receiver(args, running),
+        ProgramMode::Sender => {
+            let config = FileConfig::load(&args.config)?;
+            run_sender(args, running, config, None)?
+        }
+        ProgramMode::Receiver => run_receiver(args, running, None),
+        ProgramMode::Loopback => {
+            let config = FileConfig::load(&args.config)?;
+            run_loopback(args, running, config)?
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,294 @@
+//!
+//! Metrics of the sender & receiver exposed in the Prometheus text format over HTTP.
+//!
+
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+// ---
+use hab::common::MessageAuthentication;
+#[allow(unused_imports)]
+use hab::{debug, error, info, log_input, trace, warn};
+
+/// Upper bounds (in seconds) of the buckets of the broadcast duration histogram.
+const DURATION_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// A Prometheus histogram of durations with fixed buckets.
+#[derive(Default)]
+pub struct DurationHistogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl DurationHistogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(self.buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        _ = writeln!(out, "# HELP {name} {help}");
+        _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(self.buckets.iter()) {
+            let val = bucket.load(Ordering::Relaxed);
+            _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {val}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        _ = writeln!(out, "{name}_sum {sum}");
+        _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+///
+/// The collected metrics. The datagram-level and subscriber metrics are internal to the
+/// HAB crate and thus are not available here.
+///
+#[derive(Default)]
+pub struct Metrics {
+    // --- sender ---
+    blocks_signed: AtomicU64,
+    bytes_signed: AtomicU64,
+    broadcast_failures: AtomicU64,
+    broadcast_duration: DurationHistogram,
+    // --- receiver ---
+    blocks_received: AtomicU64,
+    bytes_received: AtomicU64,
+    blocks_verified: AtomicU64,
+    blocks_certified: AtomicU64,
+    blocks_unverified: AtomicU64,
+    receive_failures: AtomicU64,
+}
+
+impl Metrics {
+    /// Records one broadcast of the block with `size` bytes.
+    pub fn on_broadcast(&self, size: usize, duration: Duration, success: bool) {
+        if success {
+            self.blocks_signed.fetch_add(1, Ordering::Relaxed);
+            self.bytes_signed.fetch_add(size as u64, Ordering::Relaxed);
+        } else {
+            self.broadcast_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.broadcast_duration.observe(duration);
+    }
+
+    /// Records one received block with `size` bytes.
+    pub fn on_receive(&self, size: usize, authentication: &MessageAuthentication) {
+        self.blocks_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(size as u64, Ordering::Relaxed);
+        let counter = match authentication {
+            MessageAuthentication::Authenticated(_) => &self.blocks_verified,
+            MessageAuthentication::Certified(_) => &self.blocks_certified,
+            MessageAuthentication::Unverified => &self.blocks_unverified,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_receive_failure(&self) {
+        self.receive_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "blocks_signed_total",
+                "Number of signed and broadcasted blocks.",
+                &self.blocks_signed,
+            ),
+            (
+                "bytes_signed_total",
+                "Number of payload bytes signed and broadcasted.",
+                &self.bytes_signed,
+            ),
+            (
+                "broadcast_failures_total",
+                "Number of failed broadcasts.",
+                &self.broadcast_failures,
+            ),
+            (
+                "blocks_received_total",
+                "Number of received blocks.",
+                &self.blocks_received,
+            ),
+            (
+                "bytes_received_total",
+                "Number of received payload bytes.",
+                &self.bytes_received,
+            ),
+            (
+                "blocks_verified_total",
+                "Number of received authenticated blocks.",
+                &self.blocks_verified,
+            ),
+            (
+                "blocks_certified_total",
+                "Number of received certified blocks.",
+                &self.blocks_certified,
+            ),
+            (
+                "blocks_unverified_total",
+                "Number of received unverified blocks.",
+                &self.blocks_unverified,
+            ),
+            (
+                "receive_failures_total",
+                "Number of failed receives.",
+                &self.receive_failures,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            _ = writeln!(out, "# HELP {name} {help}");
+            _ = writeln!(out, "# TYPE {name} counter");
+            _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+        self.broadcast_duration.render(
+            &mut out,
+            "broadcast_duration_seconds",
+            "Time spent signing and broadcasting one block.",
+        );
+
+        out
+    }
+}
+
+///
+/// Starts the HTTP endpoint serving the metrics at `/metrics` in a background thread.
+///
+pub fn serve(metrics: Arc<Metrics>, addr: &str) {
+    let listener = TcpListener::bind(addr).expect("Failed to bind the metrics endpoint");
+    info!("Serving metrics at http://{addr}/metrics");
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_request(stream, &metrics) {
+                        warn!("Failed to serve the metrics! ERROR: {e}");
+                    }
+                }
+                Err(e) => warn!("Failed to accept the metrics connection! ERROR: {e}"),
+            }
+        }
+    });
+}
+
+fn handle_request(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// The value of the metric in the rendered metrics.
+    fn value(rendered: &str, name: &str) -> Option<String> {
+        rendered
+            .lines()
+            .find_map(|x| x.strip_prefix(&format!("{name} ")))
+            .map(|x| x.to_owned())
+    }
+
+    #[test]
+    fn test_render_counters() {
+        let metrics = Metrics::default();
+        metrics.on_broadcast(100, Duration::from_millis(20), true);
+        metrics.on_broadcast(50, Duration::from_millis(200), true);
+        metrics.on_broadcast(10, Duration::from_secs(10), false);
+        metrics.on_receive(30, &MessageAuthentication::Unverified);
+        metrics.on_receive_failure();
+
+        let rendered = metrics.render();
+        assert_eq!(value(&rendered, "blocks_signed_total").unwrap(), "2");
+        assert_eq!(value(&rendered, "bytes_signed_total").unwrap(), "150");
+        assert_eq!(value(&rendered, "broadcast_failures_total").unwrap(), "1");
+        assert_eq!(value(&rendered, "blocks_received_total").unwrap(), "1");
+        assert_eq!(value(&rendered, "bytes_received_total").unwrap(), "30");
+        assert_eq!(value(&rendered, "blocks_unverified_total").unwrap(), "1");
+        assert_eq!(value(&rendered, "blocks_verified_total").unwrap(), "0");
+        assert_eq!(value(&rendered, "receive_failures_total").unwrap(), "1");
+        assert!(rendered.contains("# TYPE blocks_signed_total counter"));
+
+        // The buckets are cumulative
+        let bucket = |le: &str| {
+            value(
+                &rendered,
+                &format!("broadcast_duration_seconds_bucket{{le=\"{le}\"}}"),
+            )
+        };
+        assert_eq!(bucket("0.01").unwrap(), "0");
+        assert_eq!(bucket("0.025").unwrap(), "1");
+        assert_eq!(bucket("0.25").unwrap(), "2");
+        assert_eq!(bucket("5").unwrap(), "2");
+        assert_eq!(bucket("+Inf").unwrap(), "3");
+        assert_eq!(
+            value(&rendered, "broadcast_duration_seconds_count").unwrap(),
+            "3"
+        );
+    }
+
+    #[test]
+    fn test_scrape() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.on_broadcast(100, Duration::from_millis(20), true);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics_clone = metrics.clone();
+        let handle = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                handle_request(stream, &metrics_clone).unwrap();
+            }
+        });
+
+        let scrape = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // In one write, so that the whole request is read before the connection is closed
+            let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = scrape("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(body, metrics.render());
+        assert_eq!(value(body, "blocks_signed_total").unwrap(), "1");
+
+        assert!(scrape("/").starts_with("HTTP/1.1 404 Not Found"));
+        handle.join().unwrap();
+    }
+}
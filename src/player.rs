@@ -0,0 +1,342 @@
+//!
+//! Plays the received audio while holding some of it back to smooth out the network jitter.
+//!
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver as MpscReceiver, RecvTimeoutError, Sender as MpscSender};
+use std::time::Duration;
+// ---
+use minimp3::{Decoder, Frame};
+use rodio::buffer::SamplesBuffer;
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::{OutputStream, OutputStreamHandle, Source};
+// ---
+#[allow(unused_imports)]
+use hab::{debug, error, info, log_input, trace, warn};
+// ---
+use crate::audio_source::find_device;
+use crate::audio_writer::ChannelReader;
+use crate::codec::{OpusStreamDecoder, OPUS_SAMPLE_RATE};
+use crate::config::AudioCodec;
+use crate::tui::ReceiverState;
+
+///
+/// Decodes the received pieces of audio and plays them on the output device
+/// (in background threads).
+///
+pub struct Player {
+    audio_tx: MpscSender<Vec<u8>>,
+}
+
+impl Player {
+    ///
+    /// The playback starts once `jitter` of audio is buffered (and starts over the same way
+    /// whenever the buffered audio runs out). The `state_tx` is notified when we wait for data.
+    /// The first output device whose name contains `output_device` is used (the default if `None`).
+    ///
+    pub fn new(
+        codec: AudioCodec,
+        jitter: Duration,
+        output_device: Option<String>,
+        state_tx: MpscSender<ReceiverState>,
+    ) -> Self {
+        let (audio_tx, audio_rx) = channel();
+        let (pcm_tx, pcm_rx) = channel();
+
+        std::thread::spawn(move || decode(codec, audio_rx, pcm_tx));
+        std::thread::spawn(move || play_decoded(jitter, output_device, pcm_rx, state_tx));
+
+        Player { audio_tx }
+    }
+
+    pub fn play(&self, piece: &[u8]) {
+        if let Err(e) = self.audio_tx.send(piece.to_vec()) {
+            warn!("Failed to pass the audio to the player! ERROR: {e}");
+        }
+    }
+}
+
+fn decode(
+    codec: AudioCodec,
+    audio_rx: MpscReceiver<Vec<u8>>,
+    pcm_tx: MpscSender<SamplesBuffer<f32>>,
+) {
+    match codec {
+        AudioCodec::Mp3 => {
+            let mut decoder = Decoder::new(ChannelReader::new(audio_rx));
+            loop {
+                let chunk = match decoder.next_frame() {
+                    Ok(Frame {
+                        data,
+                        sample_rate,
+                        channels,
+                        ..
+                    }) => {
+                        let samples = data.into_iter().map(|x| x as f32 / i16::MAX as f32);
+                        SamplesBuffer::new(
+                            channels as u16,
+                            sample_rate as u32,
+                            samples.collect::<Vec<_>>(),
+                        )
+                    }
+                    Err(minimp3::Error::Eof) => return,
+                    Err(minimp3::Error::Io(e)) => {
+                        error!("Failed to read the received audio! ERROR: {e}");
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Error decoding MP3 frame: {e:?}");
+                        continue;
+                    }
+                };
+                if pcm_tx.send(chunk).is_err() {
+                    return;
+                }
+            }
+        }
+        AudioCodec::Opus => {
+            let mut decoder = OpusStreamDecoder::new();
+            while let Ok(piece) = audio_rx.recv() {
+                let samples = decoder.decode(&piece);
+                if pcm_tx
+                    .send(SamplesBuffer::new(2, OPUS_SAMPLE_RATE, samples))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+///
+/// Opens the first output device whose name contains `name`. If no name is provided
+/// or no such device can be opened, the default output device is used (if any).
+///
+fn open_output(name: Option<&str>) -> Option<(OutputStream, OutputStreamHandle)> {
+    if let Some(name) = name {
+        let mut devices: Vec<_> = rodio::cpal::default_host()
+            .output_devices()
+            .map(|x| x.collect())
+            .unwrap_or_default();
+        let names: Vec<_> = devices
+            .iter()
+            .map(|x| x.name().unwrap_or_default())
+            .collect();
+        let device = find_device(&names, name).map(|i| devices.swap_remove(i));
+        match device.map(|x| OutputStream::try_from_device(&x)) {
+            Some(Ok(x)) => return Some(x),
+            Some(Err(e)) => {
+                warn!(
+                    "Failed to open the output device '{name}', using the default one! ERROR: {e}"
+                )
+            }
+            None => warn!(
+                "No output device matches '{name}', using the default one! Available: {names:?}"
+            ),
+        }
+    }
+
+    match OutputStream::try_default() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            error!("Failed to open the default output device! ERROR: {e}");
+            None
+        }
+    }
+}
+
+fn play_decoded(
+    jitter: Duration,
+    output_device: Option<String>,
+    pcm_rx: MpscReceiver<SamplesBuffer<f32>>,
+    state_tx: MpscSender<ReceiverState>,
+) {
+    // Without an output, the received audio is just not played
+    let (_stream, handle) = match open_output(output_device.as_deref()) {
+        Some(x) => x,
+        None => return,
+    };
+    let sink = match rodio::Sink::try_new(&handle) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to start the playback! ERROR: {e}");
+            return;
+        }
+    };
+    let mut buffer = JitterBuffer::new(jitter);
+
+    loop {
+        match pcm_rx.recv_timeout(Duration::from_millis(20)) {
+            Ok(x) => buffer.push(x),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let (chunks, state) = buffer.feed(sink.len());
+        if let Some(state) = state {
+            _ = state_tx.send(state);
+        }
+        for chunk in chunks {
+            sink.append(chunk);
+        }
+    }
+}
+
+/// Number of the chunks kept queued in the sink, so that it never waits for the next one.
+const SINK_QUEUE: usize = 2;
+
+///
+/// Holds the decoded audio until there is enough of it to start the playback. While playing,
+/// the sink only gets the audio it is about to play and the rest stays here as a cushion.
+///
+struct JitterBuffer {
+    /// How much audio must be held before the playback (re)starts.
+    target: Duration,
+    chunks: VecDeque<SamplesBuffer<f32>>,
+    /// The duration of the held audio.
+    held: Duration,
+    playing: bool,
+}
+
+impl JitterBuffer {
+    fn new(target: Duration) -> Self {
+        JitterBuffer {
+            target,
+            chunks: VecDeque::new(),
+            held: Duration::ZERO,
+            playing: false,
+        }
+    }
+
+    fn push(&mut self, chunk: SamplesBuffer<f32>) {
+        self.held += chunk.total_duration().unwrap_or_default();
+        self.chunks.push_back(chunk);
+    }
+
+    ///
+    /// Returns the audio to append to the sink that has `queued` chunks left. Once both
+    /// the sink and the cushion run dry, it starts buffering again and reports that it
+    /// waits for the data.
+    ///
+    fn feed(&mut self, queued: usize) -> (Vec<SamplesBuffer<f32>>, Option<ReceiverState>) {
+        if self.playing && queued == 0 && self.chunks.is_empty() {
+            debug!("The playback buffer ran out, buffering...");
+            self.playing = false;
+            return (vec![], Some(ReceiverState::WaitingForData));
+        }
+
+        if !self.playing {
+            if self.chunks.is_empty() || self.held < self.target {
+                return (vec![], None);
+            }
+            debug!(
+                "Starting the playback with {}ms buffered.",
+                self.held.as_millis()
+            );
+            self.playing = true;
+        }
+
+        let mut ready = vec![];
+        for _ in queued..SINK_QUEUE {
+            match self.chunks.pop_front() {
+                Some(chunk) => {
+                    self.held = self
+                        .held
+                        .saturating_sub(chunk.total_duration().unwrap_or_default());
+                    ready.push(chunk);
+                }
+                None => break,
+            }
+        }
+        (ready, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chunk of 100ms of silence.
+    fn chunk() -> SamplesBuffer<f32> {
+        SamplesBuffer::new(2, 1000, vec![0.0; 2 * 100])
+    }
+
+    #[test]
+    fn test_find_output_device() {
+        let names: Vec<String> = [
+            "default",
+            "pulse",
+            "hdmi:CARD=PCH,DEV=0",
+            "hw:CARD=PCH,DEV=0",
+        ]
+        .into_iter()
+        .map(|x| x.into())
+        .collect();
+
+        assert_eq!(find_device(&names, "pulse"), Some(1));
+        // The first one of more matching devices
+        assert_eq!(find_device(&names, "CARD=PCH"), Some(2));
+        // Else the default one is used
+        assert_eq!(find_device(&names, "CARD=USB"), None);
+    }
+
+    #[test]
+    fn test_jitter_buffer_fill_level() {
+        let mut buffer = JitterBuffer::new(Duration::from_millis(300));
+
+        for _ in 0..2 {
+            buffer.push(chunk());
+            assert!(buffer.feed(0).0.is_empty());
+        }
+        buffer.push(chunk());
+        let (ready, state) = buffer.feed(0);
+        assert_eq!(ready.len(), SINK_QUEUE);
+        assert!(state.is_none());
+
+        // The rest is kept as the cushion until the sink needs it
+        assert!(buffer.feed(SINK_QUEUE).0.is_empty());
+        assert_eq!(buffer.feed(SINK_QUEUE - 1).0.len(), 1);
+    }
+
+    #[test]
+    fn test_jitter_buffer_rebuffer() {
+        let mut buffer = JitterBuffer::new(Duration::from_millis(200));
+        buffer.push(chunk());
+        buffer.push(chunk());
+        assert_eq!(buffer.feed(0).0.len(), 2);
+
+        // The sink still plays, that is not an underrun
+        assert!(matches!(buffer.feed(1), (x, None) if x.is_empty()));
+
+        // Both ran dry
+        assert!(matches!(
+            buffer.feed(0),
+            (_, Some(ReceiverState::WaitingForData))
+        ));
+        // The playback waits for the full cushion again
+        buffer.push(chunk());
+        assert!(buffer.feed(0).0.is_empty());
+        buffer.push(chunk());
+        assert_eq!(buffer.feed(0).0.len(), 2);
+    }
+
+    #[test]
+    fn test_jitter_buffer_states() {
+        let mut buffer = JitterBuffer::new(Duration::from_millis(100));
+        let mut states = vec![];
+        let mut queued = 0;
+        // The data arrive, then stall for a while and arrive again
+        for arrives in [true, true, true, false, false, false, false, true, true] {
+            if arrives {
+                buffer.push(chunk());
+            }
+            let (ready, state) = buffer.feed(queued);
+            states.extend(state);
+            // The sink plays one chunk per step
+            queued = (queued + ready.len()).saturating_sub(1);
+        }
+
+        assert!(matches!(states[..], [ReceiverState::WaitingForData]));
+    }
+}
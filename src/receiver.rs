@@ -4,10 +4,10 @@
 
 use hab::common::MessageAuthentication;
 use hab::{utils, Receiver, ReceiverParams, ReceiverTrait};
-use rodio::Decoder as RodioDecoder;
+use std::collections::VecDeque;
 use std::io::{stdout, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::sync::Arc;
 use std::time::Duration;
 // ---
@@ -15,9 +15,18 @@ use std::time::Duration;
 #[allow(unused_imports)]
 use hab::{debug, error, info, trace, warn};
 
-use crate::config::{self, SignerInst};
-use crate::sliding_buffer::SlidingBuffer;
-use crate::tui::TerminalUiReceiver;
+use crate::audio_writer::AudioWriter;
+use crate::audit_log::AuditLog;
+use crate::config::{self, AudioCodec, PauseMode, SignerInst};
+use crate::http_out::HttpOut;
+use crate::metrics::{self, Metrics};
+use crate::player::Player;
+use crate::tui::{ReceiverCommand, ReceiverState, TerminalUiReceiver};
+
+/// How far back a sequence number can be to be considered a late block (older blocks are ignored).
+const MAX_REORDER: u64 = 1024;
+/// How much audio (in bytes) is kept while paused in the `PauseMode::Buffer` mode (the oldest is dropped).
+const MAX_HELD_SIZE: usize = 16 * 1024 * 1024;
 
 #[derive(Debug)]
 pub struct AudiBroReceiverParams {
@@ -33,99 +42,372 @@ pub struct AudiBroReceiverParams {
     pub receiver_lifetime: Duration,
     pub deliver: bool,
     pub tui: bool,
+    /// If set, the datagrams are read from this channel instead of being received over UDP.
     pub alt_input: Option<std::sync::mpsc::Receiver<Vec<u8>>>,
+    /// A codec the received audio is encoded with.
+    pub codec: AudioCodec,
+    /// A file where the received audio is written to (decoded if it ends with `.wav`).
+    pub output: Option<String>,
+    /// An address where the Prometheus metrics are served.
+    pub metrics_addr: Option<String>,
+    /// What to do with the received audio while the playback is paused.
+    pub pause_mode: PauseMode,
+    /// How much audio is buffered before the playback starts.
+    pub jitter: Duration,
+    /// A (part of the) name of the output device to play the audio on (the default if `None`).
+    pub output_device: Option<String>,
+    /// An address where the received audio is streamed over HTTP.
+    pub http_out: Option<String>,
+    /// If set, the HTTP stream also includes the certified (not only the authenticated) audio.
+    pub http_out_certified: bool,
+    /// A file where a record of the authentication of each received block is appended.
+    pub audit_log: Option<String>,
+    /// Other senders to receive from at the same time (their address & name).
+    pub other_senders: Vec<(String, String)>,
+}
+
+///
+/// The defaults match the ones of the CLI, except the `target_name` which must always be set:
+///
+/// ```ignore
+/// let params = AudiBroReceiverParams {
+///     target_addr: "127.0.0.1:5000".into(),
+///     target_name: "alice".into(),
+///     ..Default::default()
+/// };
+/// ```
+///
+impl Default for AudiBroReceiverParams {
+    fn default() -> Self {
+        AudiBroReceiverParams {
+            running: Arc::new(AtomicBool::new(true)),
+            target_addr: "127.0.0.1:5555".into(),
+            target_name: String::new(),
+            delivery_deadline: Duration::from_millis(100),
+            distribute: None,
+            heartbeat_period: Duration::from_secs(5),
+            frag_timeout: Duration::from_secs(10),
+            id_filepath: ".identity/id.bin".into(),
+            dgram_delay: Duration::from_micros(50),
+            receiver_lifetime: Duration::from_secs(10),
+            deliver: true,
+            tui: false,
+            alt_input: None,
+            codec: AudioCodec::Mp3,
+            output: None,
+            metrics_addr: None,
+            pause_mode: PauseMode::Drop,
+            jitter: Duration::ZERO,
+            output_device: None,
+            http_out: None,
+            http_out_certified: false,
+            audit_log: None,
+            other_senders: vec![],
+        }
+    }
 }
 
+#[cfg(feature = "cli")]
+impl AudiBroReceiverParams {
+    ///
+    /// Takes the parameters from the parsed command line arguments (the `running` flag
+    /// and `alt_input` are left at their defaults).
+    ///
+    pub fn from_args(args: config::Args) -> Self {
+        AudiBroReceiverParams {
+            target_addr: args.addr,
+            target_name: args.target_name,
+            delivery_deadline: Duration::from_millis(args.delivery_deadline_ms),
+            heartbeat_period: Duration::from_secs(args.heartbeat_period_s),
+            frag_timeout: Duration::from_secs(args.frag_timeout_s),
+            id_filepath: args.id_filepath,
+            receiver_lifetime: Duration::from_secs(args.receiver_lifetime_s),
+            deliver: args.deliver,
+            dgram_delay: Duration::from_micros(args.dgram_delay_us),
+            tui: args.tui,
+            distribute: args.distribute,
+            codec: args.codec,
+            output: args.output,
+            metrics_addr: args.metrics_addr,
+            pause_mode: args.pause_mode,
+            jitter: Duration::from_millis(args.jitter_ms),
+            output_device: args.output_device,
+            http_out: args.http_out,
+            http_out_certified: args.http_out_certified,
+            audit_log: args.audit_log,
+            other_senders: args.source,
+            ..Default::default()
+        }
+    }
+}
+
+///
+/// Receives from the sender `target_addr`/`target_name` and from the `other_senders`, each
+/// by its own HAB receiver. Only the first one distributes & can use the `alt_input`, the others
+/// store their identity next to it (with the name of the sender as the suffix).
+///
 pub struct AudiBroReceiver {
     params: AudiBroReceiverParams,
-    receiver: Receiver<SignerInst>,
+    /// The receivers (and the names of their senders) in the order of the senders.
+    receivers: Vec<(String, Receiver<SignerInst>)>,
 }
 
 impl AudiBroReceiver {
-    pub fn new(params: AudiBroReceiverParams) -> Self {
-        let receiver = Receiver::new(ReceiverParams {
-            running: params.running.clone(),
-            target_addr: params.target_addr.clone(),
-            target_name: params.target_name.clone(),
-            id_filename: params.id_filepath.clone(),
-            distribute: params.distribute.clone(),
-            heartbeat_period: params.heartbeat_period,
-            delivery_delay: params.delivery_deadline,
-            frag_timeout: params.frag_timeout,
-            dgram_delay: params.dgram_delay,
-            receiver_lifetime: params.receiver_lifetime,
-            deliver: params.deliver,
-            alt_input: None,
-        });
+    pub fn new(mut params: AudiBroReceiverParams) -> Self {
+        let mut receivers = vec![(
+            params.target_name.clone(),
+            Receiver::new(ReceiverParams {
+                running: params.running.clone(),
+                target_addr: params.target_addr.clone(),
+                target_name: params.target_name.clone(),
+                id_filename: params.id_filepath.clone(),
+                distribute: params.distribute.clone(),
+                heartbeat_period: params.heartbeat_period,
+                delivery_delay: params.delivery_deadline,
+                frag_timeout: params.frag_timeout,
+                dgram_delay: params.dgram_delay,
+                receiver_lifetime: params.receiver_lifetime,
+                deliver: params.deliver,
+                alt_input: params.alt_input.take(),
+            }),
+        )];
 
-        AudiBroReceiver { params, receiver }
+        for (addr, name) in &params.other_senders {
+            receivers.push((
+                name.clone(),
+                Receiver::new(ReceiverParams {
+                    running: params.running.clone(),
+                    target_addr: addr.clone(),
+                    target_name: name.clone(),
+                    id_filename: format!("{}.{name}", params.id_filepath),
+                    distribute: None,
+                    heartbeat_period: params.heartbeat_period,
+                    delivery_delay: params.delivery_deadline,
+                    frag_timeout: params.frag_timeout,
+                    dgram_delay: params.dgram_delay,
+                    receiver_lifetime: params.receiver_lifetime,
+                    deliver: params.deliver,
+                    alt_input: None,
+                }),
+            ));
+        }
+
+        AudiBroReceiver { params, receivers }
     }
 
     pub fn run(&mut self) {
-        let my_buffer = SlidingBuffer::new();
-        let my_buffer_clone = my_buffer.clone();
-
-        let (tx, rx) = channel();
-        let tx_clone = tx.clone();
+        let (playback_tx, playback_rx) = channel();
+        let (state_tx, state_rx) = channel();
+        let (control_tx, control_rx) = channel();
 
-        if self.params.tui {
+        let player = if self.params.tui {
             println!("Receiving the audio broadcast...");
-            std::thread::spawn(move || {
-                let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
-                let sink = rodio::Sink::try_new(&handle).unwrap();
-                loop {
-                    let buffer_to_play = my_buffer.clone();
-
-                    let source = match RodioDecoder::new(buffer_to_play) {
-                        Ok(x) => x,
-                        Err(_) => {
-                            if let Err(_) = tx_clone.send(config::WAITING_FOR_DATA.to_string()) {}
-                            std::thread::sleep(Duration::from_millis(500));
-                            continue;
-                        }
-                    };
+            Some(Player::new(
+                self.params.codec,
+                self.params.jitter,
+                self.params.output_device.clone(),
+                playback_tx,
+            ))
+        } else {
+            None
+        };
+
+        let audio_writer = self
+            .params
+            .output
+            .as_ref()
+            .map(|path| AudioWriter::new(path, self.params.codec));
+
+        let http_out = self
+            .params
+            .http_out
+            .as_ref()
+            .map(|addr| HttpOut::new(addr, self.params.codec));
+
+        let mut audit_log = self
+            .params
+            .audit_log
+            .as_ref()
+            .map(|path| AuditLog::new(path).expect("Failed to open the audit log"));
 
-                    sink.append(source);
-                    sink.sleep_until_end();
+        let metrics = self.params.metrics_addr.as_ref().map(|addr| {
+            let metrics = Arc::new(Metrics::default());
+            metrics::serve(metrics.clone(), addr);
+            metrics
+        });
+
+        // Each receiver receives on its own thread, the blocks are labelled by the sender index
+        let (block_tx, block_rx) = channel();
+        let mut names = vec![];
+        for (i, (name, mut receiver)) in std::mem::take(&mut self.receivers).into_iter().enumerate()
+        {
+            names.push(name);
+            let block_tx = block_tx.clone();
+            std::thread::spawn(move || loop {
+                let received = receiver.receive().map_err(|e| e.to_string());
+                // The receive loop has ended
+                if block_tx.send((i, received)).is_err() {
+                    break;
                 }
             });
         }
+        drop(block_tx);
+        // The lines printed for more senders end with the name of the sender
+        let multiple = names.len() > 1;
 
+        let running = self.params.running.clone();
+        let lost: Arc<Vec<AtomicU64>> = Arc::new(names.iter().map(|_| AtomicU64::new(0)).collect());
+        let lost_clone = lost.clone();
         let is_distributor = self.params.distribute.is_some();
-        let addr = self.params.target_addr.clone();
-        let target_name = self.params.target_name.clone();
+        let senders = std::iter::once(self.params.target_addr.clone())
+            .chain(
+                self.params
+                    .other_senders
+                    .iter()
+                    .map(|(addr, _)| addr.clone()),
+            )
+            .zip(names.iter().cloned())
+            .collect();
 
         // If should run with TUI
-        if self.params.tui {
-            std::thread::spawn(move || {
+        let tui_handle = if self.params.tui {
+            Some(std::thread::spawn(move || {
                 // Run the UI
-                let tui = TerminalUiReceiver::new(rx, addr, target_name, is_distributor);
+                let tui = TerminalUiReceiver::new(
+                    running,
+                    playback_rx,
+                    state_rx,
+                    control_tx,
+                    lost_clone,
+                    senders,
+                    is_distributor,
+                );
                 tui.run_tui();
-            });
-        }
+            }))
+        } else {
+            None
+        };
+
+        // Passes the piece of audio to the player
+        let play = |piece: &[u8]| {
+            if let Some(player) = &player {
+                player.play(piece);
+            }
+        };
+        let mut loss_trackers: Vec<_> = names.iter().map(|_| LossTracker::default()).collect();
+        // The sender whose audio is played (and written)
+        let mut selected = 0;
+        let mut pause = Pause::new(self.params.pause_mode);
 
         // The main loop as long as the app should run
         while self.params.running.load(Ordering::Acquire) {
-            let received_block = match self.receiver.receive() {
+            while let Ok(cmd) = control_rx.try_recv() {
+                match cmd {
+                    ReceiverCommand::TogglePause => {
+                        let held = pause.toggle();
+                        info!(tag: "receiver", "Playback {}.", if pause.paused { "paused" } else { "resumed" });
+                        for piece in held {
+                            play(&piece);
+                        }
+                    }
+                    ReceiverCommand::SelectSender(i) => {
+                        info!(tag: "receiver", "Playing the audio of '{}'.", names[i]);
+                        selected = i;
+                        pause.clear();
+                    }
+                }
+            }
+
+            let (i, received) = match block_rx.recv_timeout(Duration::from_millis(500)) {
+                Ok(x) => x,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let suffix = if multiple {
+                format!(";{}", names[i])
+            } else {
+                String::new()
+            };
+            let received_block = match received {
                 Ok(x) => x,
                 Err(e) => {
                     warn!("Unable to receive! ERROR: {e}");
+                    if let Some(metrics) = &metrics {
+                        metrics.on_receive_failure();
+                    }
                     continue;
                 }
             };
+            if let Some(metrics) = &metrics {
+                metrics.on_receive(received_block.message.len(), &received_block.authentication);
+            }
+            if let Some(audit_log) = &mut audit_log {
+                audit_log.record(
+                    received_block.seq,
+                    &received_block.authentication,
+                    received_block.message.len(),
+                    &utils::sha2_256_str(&received_block.message),
+                );
+            }
+            let num_lost = loss_trackers[i].on_receive(received_block.seq);
+            lost[i].store(num_lost, Ordering::Relaxed);
+
+            // The sender has stopped (only a signed notice counts, otherwise anyone could end it)
+            if received_block.message == config::END_OF_STREAM {
+                match &received_block.authentication {
+                    MessageAuthentication::Authenticated(id)
+                    | MessageAuthentication::Certified(id) => {
+                        info!(tag: "receiver", "The stream has ended.");
+                        if self.params.tui {
+                            _ = state_tx.send((i, ReceiverState::StreamEnded));
+                        } else {
+                            writeln!(
+                                stdout().lock(),
+                                "{};end;{};;;{}{suffix}",
+                                received_block.seq,
+                                id.petnames.join(","),
+                                num_lost
+                            )
+                            .unwrap();
+                        }
+                    }
+                    MessageAuthentication::Unverified => {
+                        warn!("Ignoring the unverified end of the stream!");
+                    }
+                }
+                continue;
+            }
+
+            // Only the audio of the selected sender is output (the streams can't be mixed)
+            let output = i == selected;
+            if let (true, Some(writer)) = (output, &audio_writer) {
+                writer.write(&received_block.message);
+            }
+            // Only the trusted audio goes to the listeners of the HTTP stream
+            if let (true, Some(http_out)) = (output, &http_out) {
+                let trusted = match received_block.authentication {
+                    MessageAuthentication::Authenticated(_) => true,
+                    MessageAuthentication::Certified(_) => self.params.http_out_certified,
+                    MessageAuthentication::Unverified => false,
+                };
+                if trusted {
+                    http_out.write(&received_block.message);
+                }
+            }
 
             // OUTPUT
             if self.params.tui {
-                my_buffer_clone.append(&received_block.message);
+                // The block is still verified & counted while paused, just not played
+                if output {
+                    if let Some(piece) = pause.on_piece(&received_block.message) {
+                        play(piece);
+                    }
+                }
 
-                info!(tag:"receiver", "STATUS: {}", received_block.authentication);
+                info!(tag: "receiver", "STATUS: {} ({})", received_block.authentication, names[i]);
 
-                let state_str = match received_block.authentication {
-                    MessageAuthentication::Authenticated(_) => "Authenticated",
-                    MessageAuthentication::Certified(_) => "Certified",
-                    MessageAuthentication::Unverified => "Unverified",
-                };
-                tx.send(state_str.to_string()).unwrap();
+                // The TUI may have already quit
+                _ = state_tx.send((i, ReceiverState::from(&received_block.authentication)));
             } else {
                 let mut handle = stdout().lock();
 
@@ -137,30 +419,32 @@ impl AudiBroReceiver {
                     MessageAuthentication::Authenticated(id) => {
                         writeln!(
                             handle,
-                            "{};verified;{};{};{}",
+                            "{};verified;{};{};{};{}{suffix}",
                             received_block.seq,
                             id.petnames.join(","),
                             size,
-                            hash
+                            hash,
+                            num_lost
                         )
                         .unwrap();
                     }
                     MessageAuthentication::Certified(id) => {
                         writeln!(
                             handle,
-                            "{};certified;{};{};{}",
+                            "{};certified;{};{};{};{}{suffix}",
                             received_block.seq,
                             id.petnames.join(","),
                             size,
-                            hash
+                            hash,
+                            num_lost
                         )
                         .unwrap();
                     }
                     MessageAuthentication::Unverified => {
                         writeln!(
                             handle,
-                            "{};unverified;;{};{}",
-                            received_block.seq, size, hash
+                            "{};unverified;;{};{};{}{suffix}",
+                            received_block.seq, size, hash, num_lost
                         )
                         .unwrap();
                     }
@@ -168,5 +452,251 @@ impl AudiBroReceiver {
             }
             debug!(tag: "received", "[{}][{:?}] {}", received_block.seq, received_block.authentication, &received_block.message.len());
         }
+
+        // Wait for the TUI to restore the terminal
+        if let Some(handle) = tui_handle {
+            _ = handle.join();
+        }
+    }
+}
+
+///
+/// Holds back the received audio while the playback is paused. In the `PauseMode::Buffer` mode,
+/// it keeps (up to `MAX_HELD_SIZE` of) the audio to play once resumed, otherwise it drops it.
+///
+#[derive(Debug)]
+struct Pause {
+    mode: PauseMode,
+    paused: bool,
+    /// The audio received while paused (in the `PauseMode::Buffer` mode).
+    held: VecDeque<Vec<u8>>,
+    /// The total size of the `held` pieces.
+    held_size: usize,
+}
+
+impl Pause {
+    fn new(mode: PauseMode) -> Self {
+        Pause {
+            mode,
+            paused: false,
+            held: VecDeque::new(),
+            held_size: 0,
+        }
+    }
+
+    /// Pauses or resumes the playback and returns the held audio to play (once resumed).
+    fn toggle(&mut self) -> Vec<Vec<u8>> {
+        self.paused = !self.paused;
+        if self.paused {
+            return vec![];
+        }
+        self.held_size = 0;
+        self.held.drain(..).collect()
+    }
+
+    /// Drops the held audio (e.g. when switching to another sender).
+    fn clear(&mut self) {
+        self.held_size = 0;
+        self.held.clear();
+    }
+
+    /// Returns the piece of audio if it is to be played now, otherwise holds it or drops it.
+    fn on_piece<'a>(&mut self, piece: &'a [u8]) -> Option<&'a [u8]> {
+        if !self.paused {
+            return Some(piece);
+        }
+        if self.mode == PauseMode::Buffer {
+            self.held_size += piece.len();
+            self.held.push_back(piece.to_vec());
+            // Drop the oldest audio over the limit
+            while self.held_size > MAX_HELD_SIZE {
+                let oldest = self
+                    .held
+                    .pop_front()
+                    .expect("The held size should count only the held pieces!");
+                self.held_size -= oldest.len();
+            }
+        }
+        None
+    }
+}
+
+///
+/// Counts the blocks lost in the stream based on the gaps in the sequence numbers.
+///
+#[derive(Debug, Default)]
+struct LossTracker {
+    /// The sequence number of the next block we expect (unknown until the first block).
+    next_seq: Option<u64>,
+    /// Number of blocks that never arrived so far.
+    lost: u64,
+    /// A bitset of the missing blocks among the last `MAX_REORDER` ones (indexed by `seq % MAX_REORDER`).
+    missing: [u64; (MAX_REORDER / 64) as usize],
+}
+
+impl LossTracker {
+    /// Records the block with the sequence number `seq` and returns the number of lost blocks.
+    fn on_receive(&mut self, seq: u64) -> u64 {
+        let next_seq = match self.next_seq {
+            Some(x) => x,
+            // We do not know where the stream started, so count from the first block we get
+            None => {
+                self.next_seq = Some(seq.wrapping_add(1));
+                return self.lost;
+            }
+        };
+
+        // The wrapping distances handle the wraparound at `u64::MAX`
+        let ahead = seq.wrapping_sub(next_seq);
+        let behind = next_seq.wrapping_sub(seq);
+        if ahead <= u64::MAX / 2 {
+            // The blocks in between (if any) are missing
+            self.lost += ahead;
+            self.next_seq = Some(seq.wrapping_add(1));
+            // Only the last `MAX_REORDER` blocks can still arrive late
+            for i in 0..(ahead + 1).min(MAX_REORDER) {
+                self.set_missing(seq.wrapping_sub(i), i != 0);
+            }
+        } else if behind <= MAX_REORDER {
+            // A late block was counted as lost (unless it is a duplicate)
+            if self.is_missing(seq) {
+                self.set_missing(seq, false);
+                self.lost -= 1;
+            }
+        } else {
+            // A stale (e.g. replayed) block must not move the expected one back, or the blocks
+            // up to the current one would all count as lost again
+            warn!("Ignoring the block {seq} far behind the expected {next_seq} in the loss count.");
+        }
+
+        self.lost
+    }
+
+    /// The index of the word in `missing` and the mask of the bit of the block `seq`.
+    fn bit(seq: u64) -> (usize, u64) {
+        // The `u64` wraps around at a multiple of `MAX_REORDER`, so the bits don't jump
+        let i = seq % MAX_REORDER;
+        ((i / 64) as usize, 1 << (i % 64))
+    }
+
+    fn is_missing(&self, seq: u64) -> bool {
+        let (word, mask) = Self::bit(seq);
+        self.missing[word] & mask != 0
+    }
+
+    fn set_missing(&mut self, seq: u64, missing: bool) {
+        let (word, mask) = Self::bit(seq);
+        if missing {
+            self.missing[word] |= mask;
+        } else {
+            self.missing[word] &= !mask;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "cli")]
+    use clap::Parser;
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn test_params_from_args() {
+        let args = config::Args::parse_from(["audibro", "receiver", "127.0.0.1:5555", "alice"]);
+        let params = AudiBroReceiverParams::from_args(args);
+
+        // The defaults must be the same as the ones of the CLI
+        let defaults = AudiBroReceiverParams {
+            target_name: "alice".into(),
+            ..Default::default()
+        };
+        assert_eq!(format!("{params:?}"), format!("{defaults:?}"));
+    }
+
+    /// Feeds the sequence numbers to a new tracker and returns the lost count after each.
+    fn track(seqs: &[u64]) -> Vec<u64> {
+        let mut tracker = LossTracker::default();
+        seqs.iter().map(|x| tracker.on_receive(*x)).collect()
+    }
+
+    #[test]
+    fn test_loss_tracker_hole() {
+        assert_eq!(track(&[1, 2, 5, 6]), [0, 0, 2, 2]);
+        assert_eq!(track(&[7, 8, 9]), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_loss_tracker_reorder() {
+        assert_eq!(track(&[1, 4, 3, 2, 5]), [0, 2, 1, 0, 0]);
+        // The late duplicates are not counted as found again
+        assert_eq!(track(&[1, 4, 2, 2, 4, 1, 3]), [0, 2, 1, 1, 1, 1, 0]);
+        // At most `MAX_REORDER` blocks late
+        let mut seqs: Vec<_> = (0..MAX_REORDER + 5).filter(|x| *x != 5).collect();
+        seqs.push(5);
+        assert_eq!(track(&seqs).last(), Some(&0));
+        let mut seqs: Vec<_> = (0..MAX_REORDER + 6).filter(|x| *x != 5).collect();
+        seqs.push(5);
+        assert_eq!(track(&seqs).last(), Some(&1));
+    }
+
+    #[test]
+    fn test_loss_tracker_stale() {
+        // A block far behind changes nothing, neither now nor for the next blocks
+        assert_eq!(
+            track(&[5000, 5001, 3, 5002, 5004, 5003]),
+            [0, 0, 0, 0, 1, 0]
+        );
+        assert_eq!(track(&[5000, 5000 - MAX_REORDER - 1, 5001]), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_loss_tracker_wraparound() {
+        assert_eq!(track(&[u64::MAX - 1, u64::MAX, 1, 0, 2]), [0, 0, 1, 0, 0]);
+        assert_eq!(track(&[u64::MAX - 1, 2]), [0, 3]);
+    }
+
+    #[test]
+    fn test_pause_buffer() {
+        let mut pause = Pause::new(PauseMode::Buffer);
+        assert_eq!(pause.on_piece(b"a"), Some(&b"a"[..]));
+
+        // The audio while paused is played once resumed
+        assert!(pause.toggle().is_empty());
+        assert_eq!(pause.on_piece(b"b"), None);
+        assert_eq!(pause.on_piece(b"c"), None);
+        assert_eq!(pause.toggle(), [b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(pause.on_piece(b"d"), Some(&b"d"[..]));
+
+        // Another sender's audio doesn't get the held one
+        pause.toggle();
+        assert_eq!(pause.on_piece(b"e"), None);
+        pause.clear();
+        assert!(pause.toggle().is_empty());
+    }
+
+    #[test]
+    fn test_pause_buffer_limit() {
+        let mut pause = Pause::new(PauseMode::Buffer);
+        pause.toggle();
+        for i in 0..5 {
+            assert_eq!(pause.on_piece(&vec![i; MAX_HELD_SIZE / 4]), None);
+        }
+
+        // Only the latest audio up to the limit is kept
+        let held = pause.toggle();
+        assert_eq!(held.len(), 4);
+        assert!(held.iter().zip(1..).all(|(x, i)| x[0] == i));
+    }
+
+    #[test]
+    fn test_pause_drop() {
+        let mut pause = Pause::new(PauseMode::Drop);
+        assert!(pause.toggle().is_empty());
+        assert_eq!(pause.on_piece(b"a"), None);
+
+        // The playback resumes at the live position
+        assert!(pause.toggle().is_empty());
+        assert_eq!(pause.on_piece(b"b"), Some(&b"b"[..]));
     }
 }
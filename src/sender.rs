@@ -2,31 +2,36 @@
 //! The main module providing high-level API for the sender of the data.
 //!
 
-use std::fs;
+use std::fs::{self, File};
 use std::io::stdin;
-use std::io::BufRead;
-use std::path::PathBuf;
+use std::io::{BufRead, Read};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Receiver as MpscReceiver};
+use std::sync::mpsc::{channel, Receiver as MpscReceiver, RecvTimeoutError, Sender as MpscSender};
 use std::sync::Arc;
 use std::time::Duration;
 
 // ---
 use id3::Tag;
 // ---
-use crate::audio_source::AudioFile;
+use crate::audio_source::{
+    self, AudioFile, AudioQueueReceiver, AudioQueueSender, AudioSource, AudioSourceData,
+    AudioSourceParams, PlaylistParams,
+};
 #[allow(unused_imports)]
 use hab::{debug, error, info, log_input, trace, warn};
 use hab::{Sender, SenderParams, SenderTrait};
 use id3::TagLike;
 // ---
-use crate::config::SignerInst;
+use crate::config::{self, AudioCodec, OverflowPolicy, SignerInst};
+use crate::metrics::{self, Metrics};
 use crate::tui::TerminalUi;
 
 #[derive(Debug)]
 pub struct AudiBroSenderParams {
     pub running: Arc<AtomicBool>,
     pub seed: u64,
+    /// Unused, the number of key layers is given by the rows of `key_dist`.
     pub layers: usize,
     /// An address where the sender will listen for heartbeats.
     pub addr: String,
@@ -42,6 +47,120 @@ pub struct AudiBroSenderParams {
     pub tui: bool,
     /// A directory where MP3 files for broadcaster are located.
     pub data_dir: String,
+    /// URLs of remote MP3 streams offered for broadcasting.
+    pub stream_urls: Vec<String>,
+    /// A codec used for the broadcasted audio.
+    pub codec: AudioCodec,
+    /// A bitrate of the broadcasted audio (in kbps).
+    pub audio_bitrate: u16,
+    /// A gain applied to the broadcasted audio (in dB).
+    pub gain_db: f64,
+    /// If set, the loudness of the broadcasted audio is normalized.
+    pub normalize: bool,
+    /// An address where the Prometheus metrics are served.
+    pub metrics_addr: Option<String>,
+    /// If set, the datagrams are passed to this channel instead of being sent over UDP.
+    pub alt_output: Option<MpscSender<Vec<u8>>>,
+    /// A file (or a named pipe) to broadcast instead of the lines of the standard input.
+    pub input: Option<String>,
+    /// If set, the playlists are played in a random order (seeded by `seed`).
+    pub shuffle: bool,
+    /// If set, the playlist of the TUI starts over once it ends.
+    pub repeat: bool,
+    /// A directory with MP3 files to play on a loop (without the TUI).
+    pub autoplay: Option<String>,
+    /// A (part of the) name of the input device for the microphone input.
+    pub input_device: Option<String>,
+    /// A maximum number of the captured audio pieces waiting for the broadcast.
+    pub audio_queue_size: usize,
+    /// What to do with the captured audio once the queue for the broadcast is full.
+    pub audio_overflow: OverflowPolicy,
+}
+
+///
+/// The defaults match the ones of the CLI, so one can only set the fields of interest:
+///
+/// ```ignore
+/// let params = AudiBroSenderParams {
+///     addr: "0.0.0.0:5000".into(),
+///     ..Default::default()
+/// };
+/// ```
+///
+impl Default for AudiBroSenderParams {
+    fn default() -> Self {
+        AudiBroSenderParams {
+            running: Arc::new(AtomicBool::new(true)),
+            seed: 42,
+            layers: 8,
+            addr: "0.0.0.0:5555".into(),
+            key_charges: None,
+            cert_interval: 1,
+            max_piece_size: 1024 * 1024,
+            id_filepath: ".identity/id.bin".into(),
+            dgram_size: 1500,
+            receiver_lifetime: Duration::from_secs(10),
+            key_dist: vec![vec![4, 100], vec![2, 50], vec![1, 0]],
+            dgram_delay: Duration::from_micros(50),
+            tui: false,
+            data_dir: "../../data/".into(),
+            stream_urls: vec![],
+            codec: AudioCodec::Mp3,
+            audio_bitrate: 320,
+            gain_db: 0.0,
+            normalize: false,
+            metrics_addr: None,
+            alt_output: None,
+            input: None,
+            shuffle: false,
+            repeat: false,
+            autoplay: None,
+            input_device: None,
+            audio_queue_size: 4,
+            audio_overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+impl AudiBroSenderParams {
+    ///
+    /// Takes the parameters from the parsed command line arguments and the `key_dist`
+    /// of the config file (the `running` flag and `alt_output` are left at their defaults).
+    ///
+    pub fn from_args(args: config::Args, key_dist: Vec<Vec<usize>>) -> Self {
+        AudiBroSenderParams {
+            seed: args.seed,
+            layers: args.layers,
+            addr: args.addr,
+            key_charges: args.key_charges,
+            cert_interval: args.cert_interval,
+            max_piece_size: args.max_piece_size,
+            id_filepath: args.id_filepath,
+            dgram_size: args.dgram_size,
+            receiver_lifetime: Duration::from_secs(args.receiver_lifetime_s),
+            key_dist,
+            dgram_delay: Duration::from_micros(args.dgram_delay_us),
+            tui: args.tui,
+            data_dir: args.data_dir,
+            stream_urls: args.stream_url,
+            codec: args.codec,
+            audio_bitrate: args
+                .audio_bitrate
+                .unwrap_or_else(|| args.codec.default_bitrate()),
+            gain_db: args.gain,
+            normalize: args.normalize,
+            metrics_addr: args.metrics_addr,
+            input: args.input,
+            shuffle: args.shuffle,
+            repeat: args.repeat,
+            autoplay: args.autoplay,
+            input_device: args.input_device,
+            audio_queue_size: args.audio_queue_size,
+            audio_overflow: args.audio_overflow,
+            ..Default::default()
+        }
+    }
 }
 
 pub struct AudiBroSender {
@@ -50,7 +169,7 @@ pub struct AudiBroSender {
 }
 
 impl AudiBroSender {
-    pub fn new(params: AudiBroSenderParams) -> Self {
+    pub fn new(mut params: AudiBroSenderParams) -> Self {
         let sender = Sender::new(SenderParams {
             sender_addr: params.addr.clone(),
             running: params.running.clone(),
@@ -63,50 +182,171 @@ impl AudiBroSender {
             key_dist: params.key_dist.clone(),
             key_charges: params.key_charges,
             dgram_delay: params.dgram_delay,
-            alt_output: None,
+            alt_output: params.alt_output.take(),
         });
         AudiBroSender { params, sender }
     }
 
-    pub fn run(&mut self) {
-        let (tx, mut rx) = channel();
+    ///
+    /// Broadcasts the input until the `running` flag is cleared (or the input ends).
+    /// Fails if there is nothing to autoplay or the input file can't be opened.
+    ///
+    pub fn run(&mut self) -> Result<(), String> {
+        let (tx, rx) =
+            audio_source::audio_queue(self.params.audio_queue_size, self.params.audio_overflow);
         let data_dir = self.params.data_dir.clone();
+        let stream_urls = self.params.stream_urls.clone();
+        let audio_params = AudioSourceParams {
+            codec: self.params.codec,
+            bitrate: self.params.audio_bitrate,
+            gain_db: self.params.gain_db,
+            normalize: self.params.normalize,
+            input_device: self.params.input_device.clone(),
+        };
+        let playlist_params = PlaylistParams {
+            shuffle: self.params.shuffle,
+            repeat: self.params.repeat,
+            seed: self.params.seed,
+        };
 
+        let running = self.params.running.clone();
+        // Keeps the playlist alive for the autoplay
+        let mut autoplay = None;
         // If should run with TUI
-        if self.params.tui {
-            std::thread::spawn(move || {
+        let tui_handle = if self.params.tui {
+            Some(std::thread::spawn(move || {
                 // Prepare MP3 files for broadcasting
-                let audio_files = get_audio_files(&data_dir);
+                let mut audio_files = get_audio_files(&data_dir);
+                audio_files.extend(stream_urls.into_iter().map(|url| AudioFile {
+                    artist: "Remote stream".to_owned(),
+                    title: url.clone(),
+                    filepath: url,
+                    bitrate: 0,
+                    vbr: false,
+                }));
                 // Run the UI
-                let tui = TerminalUi::new(tx);
+                let tui = TerminalUi::new(running, tx, audio_params, playlist_params);
                 tui.run_tui(&audio_files);
-            });
-        }
+            }))
+        } else {
+            // Else play the directory if should autoplay
+            if let Some(dir) = &self.params.autoplay {
+                autoplay = Some(Self::start_autoplay(
+                    dir,
+                    tx,
+                    audio_params,
+                    playlist_params,
+                )?);
+            }
+            None
+        };
+        let read_audio = self.params.tui || autoplay.is_some();
+
+        let metrics = self.params.metrics_addr.as_ref().map(|addr| {
+            let metrics = Arc::new(Metrics::default());
+            metrics::serve(metrics.clone(), addr);
+            metrics
+        });
+
+        // Opening a named pipe blocks until the writer shows up
+        let mut input_file = match (&self.params.input, read_audio) {
+            (Some(path), false) => match File::open(path) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to open the input file '{path}'! ERROR: {e}"
+                    ))
+                }
+            },
+            _ => None,
+        };
+        // Reading the standard input blocks, so it is read on its own thread
+        let stdin_rx = (!read_audio && input_file.is_none()).then(Self::spawn_stdin_reader);
 
         let mut prev = std::time::Instant::now();
         // The main loop as long as the app should run
         while self.params.running.load(Ordering::Acquire) {
-            // Get the data to broadcast from TUI mode
-            let data = if self.params.tui {
-                Self::read_input_tui(&mut rx)
+            // Get the data to broadcast from the audio source (TUI or autoplay mode)
+            let data = if read_audio {
+                match Self::read_input_audio(&rx, &self.params.running) {
+                    Some(x) => x,
+                    None => continue,
+                }
+            }
+            // Else get data from the input file
+            else if let Some(file) = &mut input_file {
+                match Self::read_input_file(file, self.params.max_piece_size) {
+                    Some(x) => x,
+                    None => {
+                        info!("The input has ended, stopping.");
+                        self.params.running.store(false, Ordering::Release);
+                        break;
+                    }
+                }
             }
             // Else get data from stream mode
-            else {
-                Self::read_input()
+            else if let Some(rx) = &stdin_rx {
+                match rx.recv_timeout(Duration::from_millis(500)) {
+                    Ok(x) => x,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        info!("The input has ended, stopping.");
+                        self.params.running.store(false, Ordering::Release);
+                        break;
+                    }
+                }
+            } else {
+                unreachable!("There is always some input.");
             };
 
-            if let Err(e) = self.sender.broadcast(data) {
-                warn!("Failed to broadcast! ERROR: {e}");
+            for piece in split_input(data, self.params.max_piece_size) {
+                let size = piece.len();
+                let start = std::time::Instant::now();
+                let res = self.sender.broadcast(piece);
+                if let Some(metrics) = &metrics {
+                    metrics.on_broadcast(size, start.elapsed(), res.is_ok());
+                }
+                if let Err(e) = res {
+                    warn!("Failed to broadcast! ERROR: {e}");
+                }
             }
             let now = std::time::Instant::now();
             warn!("TIME: {}ms", (now - prev).as_millis());
             prev = now;
         }
+
+        // Let the receivers know that the stream has ended
+        if let Err(e) = self.sender.broadcast(config::END_OF_STREAM.to_vec()) {
+            warn!("Failed to broadcast the end of the stream! ERROR: {e}");
+        }
+
+        // Wait for the TUI to restore the terminal
+        if let Some(handle) = tui_handle {
+            _ = handle.join();
+        }
+        Ok(())
     }
     // ---
 
-    /// Reads the available chunk of data from the provided input.
-    fn read_input() -> Vec<u8> {
+    ///
+    /// Passes the chunks of the standard input to the returned channel from a helper
+    /// thread, so that waiting for the input doesn't keep the main loop from noticing
+    /// that it should stop. The channel disconnects once the input ends.
+    ///
+    fn spawn_stdin_reader() -> MpscReceiver<Vec<u8>> {
+        let (tx, rx) = channel();
+        std::thread::spawn(move || {
+            while let Some(data) = Self::read_input() {
+                if tx.send(data).is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+
+    /// Reads the available chunk of data from the provided input (`None` at its end).
+    fn read_input() -> Option<Vec<u8>> {
         let input_bytes;
         #[cfg(feature = "simulate_stdin")]
         {
@@ -130,26 +370,96 @@ impl AudiBroSender {
         {
             let mut handle = stdin().lock();
             let mut input = String::new();
-            handle.read_line(&mut input).expect("Failed to read line");
+            if handle.read_line(&mut input).expect("Failed to read line") == 0 {
+                return None;
+            }
             input.pop();
             input_bytes = input.into_bytes();
         }
 
-        input_bytes
+        Some(input_bytes)
+    }
+
+    ///
+    /// Reads the next chunk (of at most `max_size` bytes) of the input file.
+    /// Returns `None` at the end of the file.
+    ///
+    fn read_input_file(file: &mut File, max_size: usize) -> Option<Vec<u8>> {
+        let mut buf = vec![0; max_size];
+        match file.read(&mut buf) {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some(buf)
+            }
+            Err(e) => {
+                error!("Failed to read the input! ERROR: {e}");
+                None
+            }
+        }
     }
 
     ///
-    /// Runs the TUI and periodically sends the input data to broadcast.
+    /// Starts playing all the MP3 files from the directory on a loop. The returned sender
+    /// must be kept alive while playing. Fails if there are no MP3 files in the directory.
     ///
-    fn read_input_tui(rx: &mut MpscReceiver<Vec<u8>>) -> Vec<u8> {
-        // Wait for the data
-        let input_bytes = match rx.recv() {
-            Ok(x) => x,
-            Err(_e) => panic!("The input is dead!"),
+    fn start_autoplay(
+        dir: &str,
+        data_tx: AudioQueueSender,
+        audio_params: AudioSourceParams,
+        playlist_params: PlaylistParams,
+    ) -> Result<MpscSender<AudioSourceData>, String> {
+        if !Path::new(dir).is_dir() {
+            return Err(format!("The directory '{dir}' to autoplay doesn't exist!"));
+        }
+        let mut files: Vec<_> = get_audio_files(dir)
+            .into_iter()
+            .map(|x| x.filepath)
+            .collect();
+        files.sort();
+        info!("Autoplaying {} files from '{dir}'.", files.len());
+
+        let (tx, rx) = channel();
+        AudioSource::new(rx, data_tx, audio_params);
+        let playlist_params = PlaylistParams {
+            repeat: true,
+            ..playlist_params
         };
+        match AudioSourceData::new_playlist(&files, playlist_params) {
+            Some(x) => tx.send(x).expect("The audio source is dead!"),
+            None => return Err(format!("There are no MP3 files in '{dir}' to autoplay!")),
+        }
+        Ok(tx)
+    }
 
-        input_bytes
+    ///
+    /// Waits for the data to broadcast from the audio source. Returns `None` if nothing came
+    /// in a while so that the caller can check if it should still run.
+    ///
+    fn read_input_audio(rx: &AudioQueueReceiver, running: &AtomicBool) -> Option<Vec<u8>> {
+        match rx.recv_timeout(Duration::from_millis(500)) {
+            Ok(x) => Some(x),
+            Err(RecvTimeoutError::Timeout) => None,
+            // The TUI drops the input once it quits
+            Err(RecvTimeoutError::Disconnected) if !running.load(Ordering::Acquire) => None,
+            Err(RecvTimeoutError::Disconnected) => panic!("The input is dead!"),
+        }
+    }
+}
+
+///
+/// Splits the input into pieces of at most `max_size` bytes, each of them is signed
+/// and broadcasted as a separate block.
+///
+fn split_input(data: Vec<u8>, max_size: usize) -> Vec<Vec<u8>> {
+    if data.len() <= max_size || max_size == 0 {
+        return vec![data];
     }
+    debug!(
+        "Splitting the input of {} bytes into pieces of {max_size} bytes.",
+        data.len()
+    );
+    data.chunks(max_size).map(|x| x.to_vec()).collect()
 }
 
 fn get_audio_files(data_dir: &str) -> Vec<AudioFile> {
@@ -166,13 +476,15 @@ fn get_audio_files(data_dir: &str) -> Vec<AudioFile> {
                 let artist = tag.artist().unwrap_or("Unknown Artist").to_owned();
                 let title = tag.title().unwrap_or("Unknown Title").to_owned();
                 warn!("Artist: {}, Title: {}", artist, title);
-                let bitrate = 0;
+                let filepath = path.to_str().unwrap().to_owned();
+                let (bitrate, vbr) = AudioFile::read_bitrate(&filepath).unwrap_or((0, false));
 
                 let file = AudioFile {
                     artist,
                     title,
-                    filepath: path.to_str().unwrap().to_owned(),
+                    filepath,
                     bitrate,
+                    vbr,
                 };
 
                 audio_files.push(file);
@@ -184,3 +496,41 @@ fn get_audio_files(data_dir: &str) -> Vec<AudioFile> {
 
     audio_files
 }
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_params_from_args() {
+        let args = config::Args::parse_from(["audibro", "sender", "0.0.0.0:5555", "alice"]);
+        let key_dist = vec![vec![4, 100], vec![2, 50], vec![1, 0]];
+        let params = AudiBroSenderParams::from_args(args, key_dist);
+
+        // The defaults must be the same as the ones of the CLI
+        assert_eq!(
+            format!("{params:?}"),
+            format!("{:?}", AudiBroSenderParams::default())
+        );
+    }
+
+    #[test]
+    fn test_missing_input_file() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("audibro-{}-missing.bin", std::process::id()));
+        let id = dir.join(format!("audibro-{}-missing-id.bin", std::process::id()));
+        let mut sender = AudiBroSender::new(AudiBroSenderParams {
+            addr: "127.0.0.1:0".into(),
+            id_filepath: id.to_str().unwrap().to_owned(),
+            input: Some(input.to_str().unwrap().to_owned()),
+            alt_output: Some(channel().0),
+            ..Default::default()
+        });
+
+        // Reported to the caller instead of panicking
+        let err = sender.run().unwrap_err();
+        assert!(err.contains("Failed to open the input file"), "{err}");
+        _ = fs::remove_file(id);
+    }
+}
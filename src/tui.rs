@@ -1,45 +1,135 @@
 use std::io::stdout;
 use std::io::Write;
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc::{channel, Receiver as MpscReceiver, Sender as MpscSender};
+use std::sync::{Arc, Once};
 use std::time::Duration;
 use std::vec;
 // ---
-use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
 use crossterm::style::{Attribute, Color, Stylize};
 use crossterm::terminal::{self, disable_raw_mode, enable_raw_mode, Clear, ClearType};
 use crossterm::{cursor, execute, queue, style};
 
+use hab::common::MessageAuthentication;
 #[allow(unused_imports)]
 use hab::{debug, error, info, log_input, trace, warn};
 // ---
-use crate::audio_source::{AudioFile, AudioSource, AudioSourceData};
+use crate::audio_source::{
+    AudioFile, AudioQueueSender, AudioSource, AudioSourceData, AudioSourceParams, PlaylistParams,
+};
 use crate::config;
 
+/// Commands the receiver TUI sends to the receive loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiverCommand {
+    /// Stops/restarts feeding the received audio to the playback.
+    TogglePause,
+    /// Plays the audio of the sender with this index.
+    SelectSender(usize),
+}
+
+/// The state of the received stream that the receiver TUI shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiverState {
+    WaitingForData,
+    StreamEnded,
+    Authenticated,
+    Certified,
+    Unverified,
+}
+
+impl ReceiverState {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ReceiverState::WaitingForData => config::WAITING_FOR_DATA,
+            ReceiverState::StreamEnded => config::STREAM_ENDED,
+            ReceiverState::Authenticated => "Authenticated",
+            ReceiverState::Certified => "Certified",
+            ReceiverState::Unverified => "Unverified",
+        }
+    }
+
+    /// The background color the state is shown with (`None` for the default one).
+    pub fn color(&self) -> Option<Color> {
+        match self {
+            ReceiverState::WaitingForData => None,
+            ReceiverState::StreamEnded => Some(Color::DarkGrey),
+            ReceiverState::Authenticated => Some(Color::Green),
+            ReceiverState::Certified => Some(Color::Yellow),
+            ReceiverState::Unverified => Some(Color::Red),
+        }
+    }
+}
+
+impl From<&MessageAuthentication> for ReceiverState {
+    fn from(authentication: &MessageAuthentication) -> Self {
+        match authentication {
+            MessageAuthentication::Authenticated(_) => ReceiverState::Authenticated,
+            MessageAuthentication::Certified(_) => ReceiverState::Certified,
+            MessageAuthentication::Unverified => ReceiverState::Unverified,
+        }
+    }
+}
+
+/// Shown once the user asks to quit.
+const QUIT_PROMPT: &str = "    Really quit? (y/n)";
+
+///
+/// Tells if the key answers the quit prompt with yes; pressing `q` (or Ctrl-C) once more
+/// also does.
+///
+fn confirms_quit(key: KeyCode) -> bool {
+    matches!(key, KeyCode::Char('y' | 'Y' | 'q') | KeyCode::Enter)
+}
+
+///
+/// Shows the state of each sender the receiver receives from (one row per sender) and plays
+/// the audio of the selected one.
+///
 pub struct TerminalUiReceiver {
-    state_rx: MpscReceiver<String>,
-    addr: String,
-    name: String,
+    running: Arc<AtomicBool>,
+    /// The state of the playback (of the selected sender).
+    playback_rx: MpscReceiver<ReceiverState>,
+    /// The states of the senders (by their index).
+    state_rx: MpscReceiver<(usize, ReceiverState)>,
+    control_tx: MpscSender<ReceiverCommand>,
+    /// Number of blocks lost in the stream of each sender so far.
+    lost: Arc<Vec<AtomicU64>>,
+    /// The address & name of each sender.
+    senders: Vec<(String, String)>,
     distribute: bool,
 }
 
 impl TerminalUiReceiver {
     pub fn new(
-        state_rx: MpscReceiver<String>,
-        addr: String,
-        name: String,
+        running: Arc<AtomicBool>,
+        playback_rx: MpscReceiver<ReceiverState>,
+        state_rx: MpscReceiver<(usize, ReceiverState)>,
+        control_tx: MpscSender<ReceiverCommand>,
+        lost: Arc<Vec<AtomicU64>>,
+        senders: Vec<(String, String)>,
         distribute: bool,
     ) -> Self {
         Self {
+            running,
+            playback_rx,
             state_rx,
-            addr,
-            name,
+            control_tx,
+            lost,
+            senders,
             distribute,
         }
     }
 
     pub fn run_tui(&self) {
-        let menu_items = vec![vec!["QUIT".into()]];
-        let menu_items_data = vec![vec!["QUIT".into()]];
+        let mut menu_items = vec![vec!["PAUSE/RESUME (space)".into()], vec!["QUIT".into()]];
+        let mut menu_items_data = vec![vec!["PAUSE".into()], vec!["QUIT".into()]];
+        if self.senders.len() > 1 {
+            menu_items[0].push("NEXT SENDER (tab)".into());
+            menu_items_data[0].push("NEXT".into());
+        }
         let menu_items_flat = menu_items
             .clone()
             .into_iter()
@@ -54,33 +144,43 @@ impl TerminalUiReceiver {
         let mut active_item = None;
 
         let mut changed = true;
-        let mut auth_state = config::WAITING_FOR_DATA.to_string();
+        let mut auth_states = vec![ReceiverState::WaitingForData; self.senders.len()];
+        // The sender whose audio is played
+        let mut selected = 0;
+        let mut paused = false;
+        let mut lost = vec![0; self.senders.len()];
+        // Waiting for the quit to be confirmed
+        let mut quitting = false;
 
-        enable_raw_mode().unwrap();
+        // Restores the terminal & stops the app once the TUI ends (even if it panics)
+        let _guard = TerminalGuard::new(self.running.clone());
         let mut stdout = stdout();
-        execute!(stdout, Clear(ClearType::All)).unwrap();
-        loop {
-            if let Ok(state) = self.state_rx.try_recv() {
-                auth_state = state;
+        while self.running.load(Ordering::Acquire) {
+            while let Ok((i, state)) = self.state_rx.try_recv() {
+                auth_states[i] = state;
+                changed = true;
+            }
+            if let Ok(state) = self.playback_rx.try_recv() {
+                auth_states[selected] = state;
                 changed = true;
             }
+            for (i, x) in self.lost.iter().enumerate() {
+                let curr_lost = x.load(Ordering::Relaxed);
+                if curr_lost != lost[i] {
+                    lost[i] = curr_lost;
+                    changed = true;
+                }
+            }
             if changed {
                 let distr_string = if self.distribute {
                     "    >>> DISTRIBUTING DATA <<<"
                 } else {
                     "    --- NOT DISTRIBUTING DATA ---"
                 };
-                let state_string = match auth_state.as_str() {
-                    "Authenticated" => format!("       {}       ", auth_state)
-                        .white()
-                        .on(Color::Green),
-                    "Certified" => format!("       {}       ", auth_state)
-                        .white()
-                        .on(Color::Yellow),
-                    "Unverified" => format!("       {}       ", auth_state)
-                        .white()
-                        .on(Color::Red),
-                    _ => format!("       {}       ", auth_state).white(),
+                let playback_string = if paused {
+                    "    || PAUSED ||".with(Color::Yellow)
+                } else {
+                    "    |> PLAYING".white()
                 };
                 queue!(
                     stdout,
@@ -90,17 +190,54 @@ impl TerminalUiReceiver {
                     cursor::MoveTo(0, 1),
                     style::Print("++++++++++++++++++++++++++++++++++++++++++"),
                     cursor::MoveToNextLine(1),
-                    style::Print(format!("    Listening to {} @ {}", self.name, self.addr)),
-                    cursor::MoveToNextLine(1),
+                )
+                .unwrap();
+                for (addr, name) in &self.senders {
+                    queue!(
+                        stdout,
+                        style::Print(format!("    Listening to {name} @ {addr}")),
+                        cursor::MoveToNextLine(1),
+                    )
+                    .unwrap();
+                }
+                queue!(
+                    stdout,
                     style::Print("++++++++++++++++++++++++++++++++++++++++++"),
                     cursor::MoveToNextLine(2),
                     style::Print(distr_string),
                     cursor::MoveToNextLine(1),
                     style::Print("------------------------------------"),
                     cursor::MoveToNextLine(1),
-                    style::PrintStyledContent(state_string),
-                    cursor::MoveToNextLine(1),
+                )
+                .unwrap();
+                // One row per sender (only its name is shown if there are more of them)
+                for (i, auth_state) in auth_states.iter().enumerate() {
+                    let state_string = format!("       {}       ", auth_state.label()).white();
+                    let state_string = match auth_state.color() {
+                        Some(color) => state_string.on(color),
+                        None => state_string,
+                    };
+                    if self.senders.len() > 1 {
+                        let cursor = if i == selected { "|>" } else { "  " };
+                        queue!(
+                            stdout,
+                            style::Print(format!("{cursor} {:<12}", self.senders[i].1)),
+                        )
+                        .unwrap();
+                    }
+                    queue!(
+                        stdout,
+                        style::PrintStyledContent(state_string),
+                        style::Print(format!("  (lost: {})", lost[i])),
+                        cursor::MoveToNextLine(1),
+                    )
+                    .unwrap();
+                }
+                queue!(
+                    stdout,
                     style::Print("------------------------------------"),
+                    cursor::MoveToNextLine(1),
+                    style::PrintStyledContent(playback_string),
                     cursor::MoveToNextLine(2),
                 )
                 .unwrap();
@@ -128,11 +265,26 @@ impl TerminalUiReceiver {
                     }
                     queue!(stdout, style::Print("---"), cursor::MoveToNextLine(1)).unwrap();
                 }
+                if quitting {
+                    queue!(
+                        stdout,
+                        style::PrintStyledContent(QUIT_PROMPT.with(Color::Yellow))
+                    )
+                    .unwrap();
+                }
                 stdout.flush().unwrap();
                 changed = false;
             }
 
             if let Some(x) = read_action() {
+                changed = true;
+                if quitting {
+                    if confirms_quit(x) {
+                        break;
+                    }
+                    quitting = false;
+                    continue;
+                }
                 match x {
                     KeyCode::Up => {
                         selected_item = selected_item.saturating_sub(1);
@@ -143,47 +295,75 @@ impl TerminalUiReceiver {
                         }
                     }
                     KeyCode::Enter => {
-                        self.process_menu_item(&menu_items_data_flat[selected_item]);
-                        active_item = Some(selected_item);
+                        let item = &menu_items_data_flat[selected_item];
+                        if item == "QUIT" {
+                            quitting = true;
+                            continue;
+                        } else if item == "PAUSE" {
+                            paused = !paused;
+                        } else if item == "NEXT" {
+                            selected = (selected + 1) % self.senders.len();
+                            self.select_sender(selected);
+                            continue;
+                        } else {
+                            active_item = Some(selected_item);
+                        }
+                        self.process_menu_item(item);
                     }
-                    KeyCode::Char('q') => break,
+                    KeyCode::Char(' ') => {
+                        paused = !paused;
+                        self.process_menu_item("PAUSE");
+                    }
+                    KeyCode::Tab if self.senders.len() > 1 => {
+                        selected = (selected + 1) % self.senders.len();
+                        self.select_sender(selected);
+                    }
+                    KeyCode::Char('q') => quitting = true,
                     _ => {}
                 };
-                changed = true;
             }
         }
-        execute!(
-            stdout,
-            style::ResetColor,
-            terminal::Clear(ClearType::All),
-            cursor::Show,
-            terminal::LeaveAlternateScreen
-        )
-        .unwrap();
-        disable_raw_mode().unwrap();
-        self.process_menu_item("QUIT");
     }
 
     fn process_menu_item(&self, item: &str) {
         info!("Processing menu item: {}", item);
 
-        if item == "QUIT" {
-            std::process::exit(0x01);
+        if item == "PAUSE" {
+            if let Err(e) = self.control_tx.send(ReceiverCommand::TogglePause) {
+                warn!("The receiver is dead! ERROR: {e}");
+            }
+        }
+    }
+
+    fn select_sender(&self, i: usize) {
+        info!("Selecting the sender: {}", self.senders[i].1);
+
+        if let Err(e) = self.control_tx.send(ReceiverCommand::SelectSender(i)) {
+            warn!("The receiver is dead! ERROR: {e}");
         }
     }
 }
 
 pub struct TerminalUi {
-    _audio_src: AudioSource,
+    running: Arc<AtomicBool>,
+    audio_src: AudioSource,
     audio_src_tx: MpscSender<AudioSourceData>,
+    playlist_params: PlaylistParams,
 }
 
 impl TerminalUi {
-    pub fn new(data_tx: MpscSender<Vec<u8>>) -> Self {
+    pub fn new(
+        running: Arc<AtomicBool>,
+        data_tx: AudioQueueSender,
+        audio_params: AudioSourceParams,
+        playlist_params: PlaylistParams,
+    ) -> Self {
         let (tx, rx) = channel();
         Self {
-            _audio_src: AudioSource::new(rx, data_tx),
+            running,
+            audio_src: AudioSource::new(rx, data_tx, audio_params),
             audio_src_tx: tx,
+            playlist_params,
         }
     }
     pub fn run_tui(&self, audio_options: &[AudioFile]) {
@@ -191,12 +371,22 @@ impl TerminalUi {
         let mut audio_files = vec![];
 
         for audio_file in audio_options.iter() {
-            audio_menu.push(format!("{} - {}", audio_file.artist, audio_file.title));
+            audio_menu.push(audio_file.label());
             audio_files.push(audio_file.filepath.clone());
         }
 
-        let menu_items = vec![audio_menu, vec!["MICROPHONE".into()], vec!["QUIT".into()]];
-        let menu_items_data = vec![audio_files, vec!["MICROPHONE".into()], vec!["QUIT".into()]];
+        let menu_items = vec![
+            audio_menu,
+            vec!["ALL (playlist)".into()],
+            vec![format!("MICROPHONE ({})", self.audio_src.device_name())],
+            vec!["QUIT".into()],
+        ];
+        let menu_items_data = vec![
+            audio_files.clone(),
+            vec!["ALL".into()],
+            vec!["MICROPHONE".into()],
+            vec!["QUIT".into()],
+        ];
         let menu_items_flat = menu_items
             .clone()
             .into_iter()
@@ -211,11 +401,13 @@ impl TerminalUi {
         let mut active_item = None;
 
         let mut changed = true;
+        // Waiting for the quit to be confirmed
+        let mut quitting = false;
 
-        enable_raw_mode().unwrap();
+        // Restores the terminal & stops the app once the TUI ends (even if it panics)
+        let _guard = TerminalGuard::new(self.running.clone());
         let mut stdout = stdout();
-        execute!(stdout, Clear(ClearType::All)).unwrap();
-        loop {
+        while self.running.load(Ordering::Acquire) {
             if changed {
                 queue!(
                     stdout,
@@ -251,11 +443,26 @@ impl TerminalUi {
                     }
                     queue!(stdout, style::Print("---"), cursor::MoveToNextLine(1)).unwrap();
                 }
+                if quitting {
+                    queue!(
+                        stdout,
+                        style::PrintStyledContent(QUIT_PROMPT.with(Color::Yellow))
+                    )
+                    .unwrap();
+                }
                 stdout.flush().unwrap();
                 changed = false;
             }
 
             if let Some(x) = read_action() {
+                changed = true;
+                if quitting {
+                    if confirms_quit(x) {
+                        break;
+                    }
+                    quitting = false;
+                    continue;
+                }
                 match x {
                     KeyCode::Up => {
                         selected_item = selected_item.saturating_sub(1);
@@ -266,48 +473,173 @@ impl TerminalUi {
                         }
                     }
                     KeyCode::Enter => {
-                        self.process_menu_item(&menu_items_data_flat[selected_item]);
+                        let item = &menu_items_data_flat[selected_item];
+                        if item == "QUIT" {
+                            quitting = true;
+                            continue;
+                        }
+                        self.process_menu_item(item, &audio_files);
                         active_item = Some(selected_item);
                     }
-                    KeyCode::Char('q') => break,
+                    KeyCode::Char('q') => quitting = true,
                     _ => {}
                 };
-                changed = true;
             }
         }
-        execute!(
-            stdout,
-            style::ResetColor,
-            terminal::Clear(ClearType::All),
-            cursor::Show,
-            terminal::LeaveAlternateScreen
-        )
-        .unwrap();
-        disable_raw_mode().unwrap();
-        self.process_menu_item("QUIT");
     }
 
-    fn process_menu_item(&self, item: &str) {
+    fn process_menu_item(&self, item: &str, audio_files: &[String]) {
         info!("Processing menu item: {}", item);
 
-        if item == "QUIT" {
-            std::process::exit(0x01);
-        }
+        let source = if item == "ALL" {
+            match AudioSourceData::new_playlist(audio_files, self.playlist_params) {
+                Some(x) => x,
+                None => return,
+            }
+        } else {
+            AudioSourceData::new_file(item)
+        };
 
-        match self.audio_src_tx.send(AudioSourceData::new_file(item)) {
+        match self.audio_src_tx.send(source) {
             Ok(x) => x,
             Err(e) => info!("ERROR: {e}"),
         };
     }
 }
 
+///
+/// Switches the terminal to the raw mode for the TUI and restores it once dropped. Since
+/// it is dropped also when the TUI thread panics, the user's shell is not left broken; a panic
+/// in another thread restores the terminal via the panic hook. Once the TUI ends, the whole app
+/// is stopped.
+///
+struct TerminalGuard {
+    running: Arc<AtomicBool>,
+}
+
+impl TerminalGuard {
+    fn new(running: Arc<AtomicBool>) -> Self {
+        static HOOK: Once = Once::new();
+        HOOK.call_once(|| {
+            let default_hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                // Restore it before the panic message is printed (so that it is readable)
+                restore_terminal();
+                default_hook(info);
+            }));
+        });
+
+        enable_raw_mode().unwrap();
+        execute!(stdout(), Clear(ClearType::All)).unwrap();
+        TerminalGuard { running }
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+        // Let the sender/receiver know that it should finish
+        self.running.store(false, Ordering::Release);
+    }
+}
+
+fn restore_terminal() {
+    _ = execute!(
+        stdout(),
+        style::ResetColor,
+        terminal::Clear(ClearType::All),
+        cursor::Show,
+        terminal::LeaveAlternateScreen
+    );
+    _ = disable_raw_mode();
+}
+
 pub fn read_action() -> Option<KeyCode> {
     if event::poll(Duration::from_millis(500)).unwrap() {
         if let Ok(Event::Key(ev)) = event::read() {
             if let KeyEventKind::Press = ev.kind {
+                // The raw mode swallows SIGINT, so Ctrl-C must be handled as a key press
+                if ev.code == KeyCode::Char('c') && ev.modifiers.contains(KeyModifiers::CONTROL) {
+                    return Some(KeyCode::Char('q'));
+                }
                 return Some(ev.code);
             }
         }
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_terminal_guard_panic() {
+        // It needs a terminal to switch to the raw mode
+        if enable_raw_mode().is_err() {
+            eprintln!("No terminal available, skipping.");
+            return;
+        }
+        disable_raw_mode().unwrap();
+
+        let running = Arc::new(AtomicBool::new(true));
+        let result = panic::catch_unwind(|| {
+            let _guard = TerminalGuard::new(running.clone());
+            assert!(terminal::is_raw_mode_enabled().unwrap());
+            panic!("The TUI has crashed!");
+        });
+
+        // The terminal is restored and the app stopped
+        assert!(result.is_err());
+        assert!(!terminal::is_raw_mode_enabled().unwrap());
+        assert!(!running.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_receiver_state() {
+        // Only the unverified one can be made here (the sender identities come from HAB)
+        assert_eq!(
+            ReceiverState::from(&MessageAuthentication::Unverified),
+            ReceiverState::Unverified
+        );
+
+        let expected = [
+            (
+                ReceiverState::WaitingForData,
+                config::WAITING_FOR_DATA,
+                None,
+            ),
+            (
+                ReceiverState::StreamEnded,
+                config::STREAM_ENDED,
+                Some(Color::DarkGrey),
+            ),
+            (
+                ReceiverState::Authenticated,
+                "Authenticated",
+                Some(Color::Green),
+            ),
+            (ReceiverState::Certified, "Certified", Some(Color::Yellow)),
+            (ReceiverState::Unverified, "Unverified", Some(Color::Red)),
+        ];
+        for (state, label, color) in expected {
+            assert_eq!(state.label(), label);
+            assert_eq!(state.color(), color);
+        }
+    }
+
+    #[test]
+    fn test_confirms_quit() {
+        for key in [KeyCode::Char('y'), KeyCode::Char('q'), KeyCode::Enter] {
+            assert!(confirms_quit(key));
+        }
+        for key in [
+            KeyCode::Char('n'),
+            KeyCode::Esc,
+            KeyCode::Char(' '),
+            KeyCode::Up,
+        ] {
+            assert!(!confirms_quit(key));
+        }
+    }
+}
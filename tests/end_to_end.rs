@@ -0,0 +1,80 @@
+//!
+//! Runs the whole path from the sender to the receiver in a single process, the two are
+//! connected by a channel (the `alt_output`/`alt_input` hooks) instead of UDP.
+//!
+#![cfg(feature = "audio")]
+
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+// ---
+use audibro::config::END_OF_STREAM;
+use audibro::{AudiBroReceiver, AudiBroReceiverParams, AudiBroSender, AudiBroSenderParams};
+
+#[test]
+fn test_end_to_end() {
+    let dir = std::env::temp_dir().join(format!("audibro-{}-end-to-end", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+
+    // The input file makes three blocks
+    let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    fs::write(path("input.bin"), &data).unwrap();
+
+    let (tx, rx) = channel();
+    let mut sender = AudiBroSender::new(AudiBroSenderParams {
+        addr: "127.0.0.1:0".into(),
+        id_filepath: path("sender.bin"),
+        max_piece_size: 1000,
+        dgram_delay: Duration::ZERO,
+        input: Some(path("input.bin")),
+        alt_output: Some(tx),
+        ..Default::default()
+    });
+    let running = Arc::new(AtomicBool::new(true));
+    let mut receiver = AudiBroReceiver::new(AudiBroReceiverParams {
+        running: running.clone(),
+        target_addr: "127.0.0.1:0".into(),
+        target_name: "alice".into(),
+        id_filepath: path("receiver.bin"),
+        dgram_delay: Duration::ZERO,
+        output: Some(path("output.bin")),
+        audit_log: Some(path("audit.log")),
+        alt_input: Some(rx),
+        ..Default::default()
+    });
+    let receiver_handle = std::thread::spawn(move || receiver.run());
+
+    // The sender stops once the input file ends
+    sender.run().expect("The input file should be broadcasted.");
+
+    // The three blocks and the end of the stream should make it through
+    let start = Instant::now();
+    let (records, output) = loop {
+        let records = fs::read_to_string(path("audit.log")).unwrap_or_default();
+        let output = fs::read(path("output.bin")).unwrap_or_default();
+        if (records.lines().count() >= 4 && output.len() >= data.len())
+            || start.elapsed() > Duration::from_secs(30)
+        {
+            break (records, output);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    running.store(false, Ordering::Release);
+    receiver_handle.join().unwrap();
+    _ = fs::remove_dir_all(&dir);
+
+    // The received audio is the input file
+    assert_eq!(output, data);
+
+    let records: Vec<Vec<_>> = records.lines().map(|x| x.split(';').collect()).collect();
+    let sizes: Vec<_> = records.iter().map(|x| x[3]).collect();
+    assert_eq!(
+        sizes,
+        ["1000", "1000", "1000", &END_OF_STREAM.len().to_string()]
+    );
+    // All of them are signed by the sender
+    assert!(records.iter().all(|x| x[2] != "unverified"), "{records:?}");
+}
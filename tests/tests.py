@@ -7,6 +7,9 @@ import shutil
 import secrets
 import time
 import hashlib
+import socket
+import signal
+import urllib.request
 
 MAX_ITERS = 100
 MSG_LEN = 128 * 1024
@@ -20,24 +23,26 @@ script_dir = os.path.dirname(script_path)
 
 
 ALICE_DIR = f"{script_dir}/env/sender_alice"
+CAROL_DIR = f"{script_dir}/env/sender_carol"
 BOB_DIR = f"{script_dir}/env/receiver_bob"
-ENV_DIRS = [ALICE_DIR, BOB_DIR]
+LOOPBACK_DIR = f"{script_dir}/env/loopback"
+ENV_DIRS = [ALICE_DIR, CAROL_DIR, BOB_DIR, LOOPBACK_DIR]
 
-def spawn_sender(cwd):
+def spawn_sender(cwd, extra_args=[], addr='0.0.0.0:5555', name='alice'):
 	os.makedirs(cwd, exist_ok=True)
 	os.chdir(cwd)
 	# Define the command to run, including any arguments
-	command = [f'../../../target/{TYPE}/audibro', '--seed=40', '--key-charges=3', '--max-piece-size=10485760', '--config=../../../config.toml', 'sender', '0.0.0.0:5555', 'alice']
+	command = [f'../../../target/{TYPE}/audibro', '--seed=40', '--key-charges=3', '--max-piece-size=10485760', '--config=../../../config.toml', *extra_args, 'sender', addr, name]
 
 	# Start the subprocess and redirect stdin/stdout to pipes
 	process = subprocess.Popen(command, cwd=cwd, stdin=subprocess.PIPE, stdout=subprocess.PIPE)
 	return process
 
-def spawn_receiver(cwd):
+def spawn_receiver(cwd, extra_args=[]):
 	os.makedirs(cwd, exist_ok=True)
 	os.chdir(cwd)
 	# Define the command to run, including any arguments
-	command = [f'../../../target/{TYPE}/audibro', '--config=../../../config.toml',  'receiver', '127.0.0.1:5555', 'alice']
+	command = [f'../../../target/{TYPE}/audibro', '--config=../../../config.toml', *extra_args, 'receiver', '127.0.0.1:5555', 'alice']
 
 
 	# Start the subprocess and redirect stdin/stdout to pipes
@@ -45,6 +50,16 @@ def spawn_receiver(cwd):
 	return process
 
 
+def spawn_loopback(cwd, extra_args=[]):
+	os.makedirs(cwd, exist_ok=True)
+	os.chdir(cwd)
+	# Define the command to run, including any arguments
+	command = [f'../../../target/{TYPE}/audibro', '--seed=40', '--config=../../../config.toml', *extra_args, 'loopback', '127.0.0.1:5556', 'alice']
+
+	# Start the subprocess and redirect stdin/stdout to pipes
+	process = subprocess.Popen(command, cwd=cwd, stdin=subprocess.PIPE, stdout=subprocess.PIPE)
+	return process
+
 def clear_env(dirs):
 	for directory in dirs:
 		if os.path.exists(directory):
@@ -107,11 +122,223 @@ def test_scenarios(dir):
 				time.sleep(1)
 				print(f"Scenario '{file}' paased")
 
+def test_loopback():
+	clear_env(ENV_DIRS)
+	print("Testing loopback")
+
+	ps = spawn_loopback(LOOPBACK_DIR, ['--metrics-addr=127.0.0.1:9099'])
+	time.sleep(1)
+	for seq in range(1, 6):
+		msg = f"{seq}"
+		ps.stdin.write(f"{msg}\r\n".encode())
+		ps.stdin.flush()
+		time.sleep(0.5)
+
+		act_output = ps.stdout.readline().decode()
+		toks = act_output.strip().split(';')
+		assert toks[1] in ("verified", "certified"), f"Block {seq} not authenticated: {act_output}"
+		assert toks[4] == sha256(msg + "\r"), f"Block {seq} mismatch: {act_output}"
+		assert toks[5] == "0", f"Blocks lost: {act_output}"
+
+	# Both sides run fine with the metrics enabled
+	metrics = urllib.request.urlopen("http://127.0.0.1:9099/metrics", timeout=5).read().decode()
+	assert "blocks_signed_total 5" in metrics, f"Unexpected metrics: {metrics}"
+
+	ps.terminate()
+	time.sleep(1)
+	print("Loopback passed")
+
+def test_input_file():
+	clear_env(ENV_DIRS)
+	print("Testing the file input")
+
+	os.makedirs(ALICE_DIR, exist_ok=True)
+	fifo = f"{ALICE_DIR}/input.fifo"
+	os.mkfifo(fifo)
+	output = f"{BOB_DIR}/received.bin"
+
+	ps_alice = spawn_sender(ALICE_DIR, [f'--input={fifo}'])
+	ps_bob = spawn_receiver(BOB_DIR, [f'--output={output}'])
+	time.sleep(2)
+
+	# Arbitrary binary data, not just lines
+	data = secrets.token_bytes(MSG_LEN)
+	with open(fifo, 'wb') as f:
+		for i in range(0, len(data), 4096):
+			f.write(data[i:i + 4096])
+			f.flush()
+			time.sleep(0.05)
+	time.sleep(2)
+
+	ps_alice.terminate()
+	ps_bob.terminate()
+	time.sleep(1)
+
+	with open(output, 'rb') as f:
+		assert f.read() == data, "Received data mismatch!"
+	# The sender stopped at the end of the input and said so
+	last_output = ps_bob.stdout.read().decode().splitlines()[-1]
+	assert last_output.split(';')[1] == "end", f"No end of the stream: {last_output}"
+	print("File input passed")
+
+def test_large_input():
+	clear_env(ENV_DIRS)
+	print("Testing the input larger than the max piece size")
+
+	max_piece_size = 1024
+	ps = spawn_loopback(LOOPBACK_DIR, [f'--max-piece-size={max_piece_size}'])
+	time.sleep(1)
+
+	msg = "".join(random.choice(string.ascii_letters) for _ in range(3 * max_piece_size)) + "\r"
+	ps.stdin.write(f"{msg}\n".encode())
+	ps.stdin.flush()
+	time.sleep(1)
+
+	# Expect the line split into the pieces of the max size
+	pieces = [msg[i:i + max_piece_size] for i in range(0, len(msg), max_piece_size)]
+	for piece in pieces:
+		toks = ps.stdout.readline().decode().strip().split(';')
+		assert int(toks[3]) == len(piece), f"Unexpected piece size {toks[3]}!"
+		assert toks[4] == sha256(piece), "Piece mismatch!"
+
+	ps.terminate()
+	time.sleep(1)
+	print("Large input passed")
+
+def test_autoplay():
+	clear_env(ENV_DIRS)
+	print("Testing the autoplay")
+
+	ps = spawn_loopback(LOOPBACK_DIR, [f'--autoplay={script_dir}/../data/'])
+	# One piece carries about two seconds of the audio
+	time.sleep(5)
+
+	for _ in range(2):
+		toks = ps.stdout.readline().decode().strip().split(';')
+		assert toks[1] in ("verified", "certified"), f"Audio not authenticated: {toks}"
+		assert int(toks[3]) > 0, "Empty audio piece!"
+
+	ps.terminate()
+	time.sleep(1)
+	print("Autoplay passed")
+
+def test_http_out():
+	clear_env(ENV_DIRS)
+	print("Testing the HTTP output")
+
+	ps = spawn_loopback(LOOPBACK_DIR, ['--http-out=127.0.0.1:8088', '--http-out-certified'])
+	time.sleep(1)
+
+	listener = socket.create_connection(("127.0.0.1", 8088))
+	listener.sendall(b"GET /stream HTTP/1.1\r\nHost: localhost\r\n\r\n")
+	response = b""
+	while b"\r\n\r\n" not in response:
+		response += listener.recv(1024)
+	headers, body = response.split(b"\r\n\r\n", 1)
+	assert headers.startswith(b"HTTP/1.1 200"), f"Unexpected response: {headers}"
+
+	msg = "".join(random.choice(string.ascii_letters) for _ in range(64))
+	ps.stdin.write(f"{msg}\n".encode())
+	ps.stdin.flush()
+	ps.stdout.readline()
+
+	listener.settimeout(5)
+	while len(body) < len(msg):
+		body += listener.recv(1024)
+	assert body == msg.encode(), f"Streamed data mismatch: {body}"
+
+	listener.close()
+	ps.terminate()
+	time.sleep(1)
+	print("HTTP output passed")
+
+def test_audit_log():
+	clear_env(ENV_DIRS)
+	print("Testing the audit log")
+
+	audit_log = f"{LOOPBACK_DIR}/audit.log"
+	ps = spawn_loopback(LOOPBACK_DIR, [f'--audit-log={audit_log}'])
+	time.sleep(1)
+
+	msgs = [f"block-{i}" for i in range(3)]
+	for msg in msgs:
+		ps.stdin.write(f"{msg}\n".encode())
+		ps.stdin.flush()
+		ps.stdout.readline()
+
+	ps.terminate()
+	time.sleep(1)
+
+	with open(audit_log) as f:
+		records = [x.strip().split(';') for x in f.readlines()]
+	# The last record may be the end of the stream
+	records = [x for x in records if x[4] != sha256("\0AUDIBRO:END-OF-STREAM\0")]
+	assert len(records) == len(msgs), f"Unexpected number of records: {records}"
+	for msg, rec in zip(msgs, records):
+		assert len(rec) == 5, f"Malformed record: {rec}"
+		assert rec[2] in ("verified", "certified"), f"Block not authenticated: {rec}"
+		assert int(rec[3]) == len(msg), f"Unexpected size: {rec}"
+		assert rec[4] == sha256(msg), f"Hash mismatch: {rec}"
+	print("Audit log passed")
+
+def test_shutdown():
+	clear_env(ENV_DIRS)
+	print("Testing the shutdown on Ctrl-C")
+
+	ps = spawn_sender(ALICE_DIR)
+	time.sleep(1)
+
+	# A single Ctrl-C must be enough even while waiting for the input
+	ps.send_signal(signal.SIGINT)
+	try:
+		ret = ps.wait(timeout=5)
+	except subprocess.TimeoutExpired:
+		ps.kill()
+		assert False, "The sender didn't shut down on Ctrl-C!"
+	assert ret == 0, f"Unexpected exit code {ret}!"
+	print("Shutdown passed")
+
+def test_multiple_senders():
+	clear_env(ENV_DIRS)
+	print("Testing the multiple senders")
+
+	ps_alice = spawn_sender(ALICE_DIR)
+	ps_carol = spawn_sender(CAROL_DIR, addr='0.0.0.0:5557', name='carol')
+	ps_bob = spawn_receiver(BOB_DIR, ['--source=127.0.0.1:5557:carol'])
+	time.sleep(2)
+
+	# Each line is labelled by its sender and the senders are tracked separately
+	for (ps, name, seq) in [(ps_alice, 'alice', 1), (ps_carol, 'carol', 1), (ps_alice, 'alice', 2), (ps_carol, 'carol', 2), (ps_carol, 'carol', 3)]:
+		msg = f"{name}-{seq}"
+		ps.stdin.write(f"{msg}\r\n".encode())
+		ps.stdin.flush()
+		time.sleep(0.5)
+
+		act_output = ps_bob.stdout.readline().decode()
+		toks = act_output.strip().split(';')
+		assert toks[-1] == name, f"Block of '{name}' not labelled: {act_output}"
+		assert toks[4] == sha256(msg + "\r"), f"Block of '{name}' mismatch: {act_output}"
+		assert toks[5] == "0", f"Blocks of '{name}' lost: {act_output}"
+
+	ps_alice.terminate()
+	ps_carol.terminate()
+	ps_bob.terminate()
+	time.sleep(1)
+	print("Multiple senders passed")
+
 # Run the function as the main program
 if __name__ == '__main__':
 	clear_env(ENV_DIRS)
 
 	test_scenarios(f"{script_dir}/scenarios/")
+	test_loopback()
+	test_input_file()
+	test_large_input()
+	test_autoplay()
+	test_http_out()
+	test_audit_log()
+	test_shutdown()
+	test_multiple_senders()
 	ps_alice = spawn_sender(ALICE_DIR)
 	ps_bob = spawn_receiver(BOB_DIR)
 	time.sleep(1)